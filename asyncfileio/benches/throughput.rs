@@ -0,0 +1,140 @@
+//! 合成した連番で読み込みスレッドの持続的なフレームレートを測る
+//!
+//! cargo bench --bench throughput -- [obj|png] [フレーム数]
+use anyhow::Result;
+use asyncfileio::{AsyncFileReader, Buffer, Metrics, Msg, Paths, ReaderConfig, TryRecvError};
+use parser::wavefrontobj::{parse_obj, write_obj, Model};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 1辺nの格子状のメッシュ (frameごとに高さを変える)
+fn grid(n: usize, frame: usize) -> Model {
+    let mut m = Model::default();
+    for y in 0..n {
+        for x in 0..n {
+            let h = ((x + y + frame) as f32 * 0.1).sin();
+            m.v.push(vec![x as f32, y as f32, h]);
+            m.vn.push(vec![0.0, 0.0, 1.0]);
+            m.vt.push(vec![x as f32 / n as f32, y as f32 / n as f32]);
+        }
+    }
+    for y in 0..n as i64 - 1 {
+        for x in 0..n as i64 - 1 {
+            let i = |x: i64, y: i64| {
+                let i = y * n as i64 + x + 1;
+                vec![i, i, i]
+            };
+            m.f.push(vec![i(x, y), i(x + 1, y), i(x + 1, y + 1)]);
+            m.f.push(vec![i(x, y), i(x + 1, y + 1), i(x, y + 1)]);
+        }
+    }
+    m
+}
+
+fn write_obj_sequence(dir: &Path, frames: usize) -> Paths {
+    (0..frames)
+        .map(|i| {
+            let path = dir.join(format!("{}.obj", i));
+            let file = std::fs::File::create(&path).unwrap();
+            write_obj(std::io::BufWriter::new(file), &grid(128, i), None).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+fn write_png_sequence(dir: &Path, frames: usize) -> Paths {
+    (0..frames)
+        .map(|i| {
+            let path = dir.join(format!("{}.png", i));
+            let im = image::RgbaImage::from_fn(1024, 1024, |x, y| {
+                let v = ((x ^ y) as usize + i) as u8;
+                image::Rgba([v, x as u8, y as u8, 255])
+            });
+            im.save(&path).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+fn decode_obj(buf: Buffer) -> Result<usize> {
+    Ok(parse_obj(buf.as_ref())?.1.len())
+}
+
+fn decode_png(buf: Buffer) -> Result<usize> {
+    Ok(image::load_from_memory(&buf)?.raw_pixels().len())
+}
+
+/// 3周読んだ時のフレーム/秒と統計
+fn measure(
+    paths: &Arc<Paths>,
+    decoder: fn(Buffer) -> Result<usize>,
+    config: ReaderConfig,
+) -> (f64, Metrics) {
+    let mut reader = AsyncFileReader::spawn_with(Arc::clone(paths), decoder, config);
+    let total = paths.len() * 3;
+    let start = Instant::now();
+    let mut received = 0;
+    while received < total {
+        match reader.try_recv() {
+            Ok(res) => {
+                res.unwrap();
+                received += 1;
+            }
+            Err(TryRecvError::Empty) => std::thread::sleep(Duration::from_micros(100)),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+    let fps = total as f64 / start.elapsed().as_secs_f64();
+    let metrics = reader.metrics();
+    reader.send(Msg::Terminate).unwrap();
+    (fps, metrics)
+}
+
+fn main() {
+    // cargo benchが付ける--benchは読み飛ばす
+    let args: Vec<_> = std::env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let kind = args.first().map_or("obj", |s| s.as_str());
+    let frames = args
+        .get(1)
+        .map_or(32, |s| s.parse().expect("failed to parse frames"));
+    let dir = std::env::temp_dir().join(format!("asyncfileio_bench_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (paths, decoder): (_, fn(Buffer) -> Result<usize>) = match kind {
+        "obj" => (write_obj_sequence(&dir, frames), decode_obj),
+        "png" => (write_png_sequence(&dir, frames), decode_png),
+        _ => panic!("unknown kind: {}", kind),
+    };
+    let paths = Arc::new(paths);
+    println!("{} x {} frames", kind, frames);
+    println!(
+        "{:>6} {:>8} {:>10} {:>8} {:>10} {:>10} {:>8} {:>8}",
+        "io", "decode", "frames/s", "MB/s", "read", "decode", "dropped", "pool hit"
+    );
+    for io in [1, 4] {
+        for decode_workers in [1, 2, 4, 8] {
+            let config = ReaderConfig {
+                lookahead: 16,
+                decode_workers,
+                io_concurrency: io,
+                ..Default::default()
+            };
+            let (fps, m) = measure(&paths, decoder, config);
+            println!(
+                "{:>6} {:>8} {:>10.1} {:>8.1} {:>10.1?} {:>10.1?} {:>8} {:>7.0}%",
+                io,
+                decode_workers,
+                fps,
+                m.bytes_per_sec() / 1e6,
+                m.read.mean(),
+                m.decode.mean(),
+                m.cancelled + m.discarded,
+                100.0 * m.pool.hits as f64 / (m.pool.hits + m.pool.misses).max(1) as f64
+            );
+        }
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,412 @@
+use crate::{Buffer, FrameSource, Mmap};
+use anyhow::{bail, ensure, Context, Result};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// mmapしたまま数値型として読めるエントリの境界
+/// zipのエントリはローカルヘッダの長さ次第でずれるので，ずれたものはコピーする
+const ALIGN: u64 = 8;
+
+/// アーカイブ内の1ファイル
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    offset: u64,
+    len: u64,
+}
+
+/// 無圧縮のzipまたはtarに入った連番
+/// 名前に含まれる数字の大小順にフレームを並べる
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    /// mappedで開いた場合はファイル全体のマップ
+    map: Option<Arc<Mmap>>,
+}
+
+impl Archive {
+    /// 拡張子からzipかtarかを決める
+    /// suffixで終わる名前のファイルだけをフレームにする
+    pub fn open(path: impl AsRef<Path>, suffix: &str) -> Result<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("zip") => Self::zip(path, suffix),
+            Some("tar") => Self::tar(path, suffix),
+            _ => bail!("unknown archive type: {:?}", path),
+        }
+    }
+    /// 圧縮されたエントリがあれば失敗する (zip64は未対応)
+    pub fn zip(path: impl AsRef<Path>, suffix: &str) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        // 末尾のコメントは最大で65535バイト
+        let tail_len = size.min(22 + 0xffff);
+        let mut tail = vec![0; tail_len as usize];
+        file.seek(SeekFrom::Start(size - tail_len))?;
+        file.read_exact(&mut tail)?;
+        let eocd = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&i| tail[i..].starts_with(b"PK\x05\x06"))
+            .with_context(|| format!("not a zip file: {:?}", path))?;
+        let eocd = &tail[eocd..];
+        let count = le(&eocd[10..12]);
+        let (dir_len, dir_offset) = (le(&eocd[12..16]), le(&eocd[16..20]));
+        ensure!(
+            count != 0xffff && dir_offset != 0xffff_ffff,
+            "zip64 is not supported: {:?}",
+            path
+        );
+        let mut dir = vec![0; dir_len as usize];
+        file.seek(SeekFrom::Start(dir_offset))?;
+        file.read_exact(&mut dir)?;
+        let mut entries = vec![];
+        let mut rest = &dir[..];
+        for _ in 0..count {
+            ensure!(
+                rest.len() >= 46 && rest.starts_with(b"PK\x01\x02"),
+                "broken zip directory: {:?}",
+                path
+            );
+            let method = le(&rest[10..12]);
+            let len = le(&rest[20..24]);
+            let name_len = le(&rest[28..30]) as usize;
+            let skip = 46 + name_len + le(&rest[30..32]) as usize + le(&rest[32..34]) as usize;
+            let local = le(&rest[42..46]);
+            ensure!(rest.len() >= skip, "broken zip directory: {:?}", path);
+            let name = String::from_utf8_lossy(&rest[46..46 + name_len]).into_owned();
+            rest = &rest[skip..];
+            if name.ends_with('/') || !name.ends_with(suffix) {
+                continue;
+            }
+            ensure!(method == 0, "compressed zip entry: {}", name);
+            // データの位置はローカルヘッダの可変長部分の後ろ
+            let mut header = [0; 30];
+            file.seek(SeekFrom::Start(local))?;
+            file.read_exact(&mut header)?;
+            ensure!(
+                header.starts_with(b"PK\x03\x04"),
+                "broken zip entry: {}",
+                name
+            );
+            let offset = local + 30 + le(&header[26..28]) + le(&header[28..30]);
+            entries.push(Entry { name, offset, len });
+        }
+        Self::new(path, entries, size)
+    }
+    /// ustarとGNU/paxの長い名前に対応する
+    pub fn tar(path: impl AsRef<Path>, suffix: &str) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let mut entries = vec![];
+        let mut long_name = None;
+        let mut header = [0; 512];
+        let mut offset = 0;
+        while offset + 512 <= size {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut header)?;
+            // 空のブロックで終わる
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let len =
+                octal(&header[124..136]).with_context(|| format!("broken tar: {:?}", path))?;
+            let data = offset + 512;
+            offset = data + len.div_ceil(512) * 512;
+            let name = match long_name.take() {
+                Some(name) => name,
+                None if &header[257..262] == b"ustar" && header[345] != 0 => {
+                    format!("{}/{}", cstr(&header[345..500]), cstr(&header[..100]))
+                }
+                None => cstr(&header[..100]),
+            };
+            match header[156] {
+                b'0' | 0 if name.ends_with(suffix) => entries.push(Entry {
+                    name,
+                    offset: data,
+                    len,
+                }),
+                // 次のエントリの名前
+                b'L' | b'x' => {
+                    let mut buf = vec![0; len as usize];
+                    file.read_exact(&mut buf)?;
+                    long_name = match header[156] {
+                        b'L' => Some(cstr(&buf)),
+                        _ => pax_path(&buf),
+                    };
+                }
+                _ => {}
+            }
+        }
+        Self::new(path, entries, size)
+    }
+    fn new(path: PathBuf, mut entries: Vec<Entry>, size: u64) -> Result<Self> {
+        ensure!(
+            entries.iter().all(|e| e.offset + e.len <= size),
+            "truncated archive: {:?}",
+            path
+        );
+        entries.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+        Ok(Self {
+            path,
+            entries,
+            map: None,
+        })
+    }
+    /// コピーせずにmmapして読む (境界のずれたzipのエントリだけはコピーする)
+    pub fn mapped(mut self) -> Result<Self> {
+        self.map = Some(Arc::new(Mmap::open(&self.path)?));
+        Ok(self)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// アーカイブ内でのフレームの名前
+    pub fn entry_name(&self, index: usize) -> &str {
+        &self.entries[index].name
+    }
+}
+
+impl FrameSource for Archive {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+    fn name(&self, index: usize) -> String {
+        format!("{}#{}", self.path.display(), self.entries[index].name)
+    }
+    fn size_hint(&self, index: usize) -> Option<usize> {
+        let Entry { offset, len, .. } = self.entries[index];
+        (self.map.is_none() || offset % ALIGN != 0).then_some(len as usize)
+    }
+    fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+        let Entry { offset, len, .. } = self.entries[index];
+        if let Some(map) = &self.map {
+            let range = offset as usize..(offset + len) as usize;
+            if offset % ALIGN != 0 {
+                buf.as_mut().extend_from_slice(&map[range]);
+                return Ok(buf);
+            }
+            return Ok(buf.into_mapped(Arc::clone(map), range));
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.take(len).read_to_end(buf.as_mut())?;
+        ensure!(read as u64 == len, "truncated archive: {:?}", self.path);
+        Ok(buf)
+    }
+}
+
+/// リトルエンディアンの整数
+fn le(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0, |x, &b| x << 8 | b as u64)
+}
+
+/// tarの数値 (8進数の文字列，または先頭ビットが立っていればビッグエンディアン)
+fn octal(b: &[u8]) -> Option<u64> {
+    if b[0] & 0x80 != 0 {
+        return Some(b[1..].iter().fold(0, |x, &b| x << 8 | b as u64));
+    }
+    let s = std::str::from_utf8(b).ok()?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    match s {
+        "" => Some(0),
+        s => u64::from_str_radix(s, 8).ok(),
+    }
+}
+
+/// NUL終端の文字列
+fn cstr(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).into_owned()
+}
+
+/// paxヘッダ ("長さ key=value\n"の並び) からpathを取り出す
+fn pax_path(mut b: &[u8]) -> Option<String> {
+    let mut path = None;
+    while let Some(space) = b.iter().position(|&c| c == b' ') {
+        let len: usize = std::str::from_utf8(&b[..space]).ok()?.parse().ok()?;
+        let record = b.get(space + 1..len)?.strip_suffix(b"\n")?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        b = &b[len..];
+    }
+    path
+}
+
+/// 数字の並びを数として比べる ("frame_2" < "frame_10")
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+                let (n, m) = (digits(a), digits(b));
+                let zeros = |s: &[u8]| s.iter().take_while(|&&c| c == b'0').count();
+                let (x, y) = (&a[zeros(&a[..n])..n], &b[zeros(&b[..m])..m]);
+                let ord = x.len().cmp(&y.len()).then(x.cmp(y)).then(n.cmp(&m));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[n..];
+                b = &b[m..];
+            }
+            (Some(x), Some(y)) if x == y => {
+                a = &a[1..];
+                b = &b[1..];
+            }
+            (x, y) => return x.cmp(&y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BufPool;
+
+    const FRAMES: [(&str, &[u8]); 4] = [
+        ("seq/frame_10.obj", b"ten"),
+        ("seq/frame_2.obj", b"two"),
+        ("seq/readme.txt", b"skip"),
+        ("seq/frame_1.obj", b""),
+    ];
+
+    /// 無圧縮のzipを作る
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let (mut out, mut dir) = (vec![], vec![]);
+        let u16 = |x: usize| (x as u16).to_le_bytes();
+        let u32 = |x: usize| (x as u32).to_le_bytes();
+        for (name, data) in files {
+            let local = out.len();
+            out.extend_from_slice(b"PK\x03\x04\x14\0\0\0\0\0\0\0\0\0\0\0\0\0");
+            out.extend_from_slice(&u32(data.len()));
+            out.extend_from_slice(&u32(data.len()));
+            out.extend_from_slice(&u16(name.len()));
+            // ローカルヘッダにだけextraを付ける
+            out.extend_from_slice(&u16(4));
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b"\0\0\0\0");
+            out.extend_from_slice(data);
+            dir.extend_from_slice(b"PK\x01\x02\x14\0\x14\0\0\0\0\0\0\0\0\0\0\0\0\0");
+            dir.extend_from_slice(&u32(data.len()));
+            dir.extend_from_slice(&u32(data.len()));
+            dir.extend_from_slice(&u16(name.len()));
+            dir.extend_from_slice(&[0; 12]);
+            dir.extend_from_slice(&u32(local));
+            dir.extend_from_slice(name.as_bytes());
+        }
+        let offset = out.len();
+        out.extend_from_slice(&dir);
+        out.extend_from_slice(b"PK\x05\x06\0\0\0\0");
+        out.extend_from_slice(&u16(files.len()));
+        out.extend_from_slice(&u16(files.len()));
+        out.extend_from_slice(&u32(dir.len()));
+        out.extend_from_slice(&u32(offset));
+        out.extend_from_slice(&u16(0));
+        out
+    }
+
+    fn tar_header(name: &str, len: usize, kind: u8) -> [u8; 512] {
+        let mut h = [0; 512];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[124..135].copy_from_slice(format!("{:011o}", len).as_bytes());
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut push = |h: [u8; 512], data: &[u8]| {
+            out.extend_from_slice(&h);
+            out.extend_from_slice(data);
+            out.resize(out.len().div_ceil(512) * 512, 0);
+        };
+        for (name, data) in files {
+            push(tar_header(name, data.len(), b'0'), data);
+        }
+        // 100バイトを超える名前
+        let long = format!("seq/frame_3_{}.obj", "l".repeat(120));
+        push(
+            tar_header("././@LongLink", long.len(), b'L'),
+            long.as_bytes(),
+        );
+        push(tar_header("ignored", 5, b'0'), b"three");
+        let record = format!(" path=seq/frame_4_{}.obj\n", "p".repeat(120));
+        let pax = format!("{}{}", record.len() + 3, record);
+        push(tar_header("pax", pax.len(), b'x'), pax.as_bytes());
+        push(tar_header("ignored", 4, b'0'), b"four");
+        push(tar_header("seq/", 0, b'5'), b"");
+        out.extend_from_slice(&[0; 1024]);
+        out
+    }
+
+    fn read_all(archive: &Archive) -> Vec<Vec<u8>> {
+        let pool = BufPool::default();
+        (0..archive.len())
+            .map(|i| archive.read(i, pool.get_buffer()).unwrap().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = ["f10", "f2", "f02", "f1a", "f1", "e"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["e", "f1", "f1a", "f2", "f02", "f10"]);
+    }
+
+    #[test]
+    fn test_zip() {
+        let path = std::env::temp_dir().join(format!("asyncfileio_{}.zip", std::process::id()));
+        std::fs::write(&path, zip(&FRAMES)).unwrap();
+        let archive = Archive::open(&path, ".obj").unwrap();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.entry_name(0), "seq/frame_1.obj");
+        assert_eq!(archive.size_hint(2), Some(3));
+        let expected = [&b""[..], b"two", b"ten"];
+        assert_eq!(read_all(&archive), expected);
+        let mapped = archive.mapped().unwrap();
+        assert_eq!(read_all(&mapped), expected);
+        // 境界のずれたエントリはコピーする
+        let pool = BufPool::default();
+        for (i, e) in mapped.entries.iter().enumerate() {
+            let buf = mapped.read(i, pool.get_buffer()).unwrap();
+            assert_eq!(buf.is_mapped(), e.offset % ALIGN == 0);
+            assert!(buf.is_empty() || buf.as_ptr() as u64 % ALIGN == 0);
+        }
+        // 圧縮されたエントリは読めない
+        let mut data = zip(&FRAMES[..1]);
+        let dir = data.len() - 22 - 46 - FRAMES[0].0.len();
+        data[dir + 10] = 8;
+        std::fs::write(&path, data).unwrap();
+        assert!(Archive::zip(&path, "").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_tar() {
+        let path = std::env::temp_dir().join(format!("asyncfileio_{}.tar", std::process::id()));
+        std::fs::write(&path, tar(&FRAMES)).unwrap();
+        let archive = Archive::open(&path, ".obj").unwrap();
+        assert_eq!(archive.len(), 5);
+        assert!(archive.entry_name(2).ends_with("lll.obj"));
+        assert!(archive.entry_name(3).ends_with("ppp.obj"));
+        let expected = [&b""[..], b"two", b"three", b"four", b"ten"];
+        assert_eq!(read_all(&archive), expected);
+        let mapped = archive.mapped().unwrap();
+        assert_eq!(read_all(&mapped), expected);
+        // tarのエントリは512バイト境界なのでコピーしない
+        assert!(mapped
+            .read(1, BufPool::default().get_buffer())
+            .unwrap()
+            .is_mapped());
+        assert!(Archive::open(path.with_extension("rar"), "").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,86 +1,285 @@
-use std::sync::mpsc;
+use crate::Mmap;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Mutex, Weak};
+
+/// 貯めておくバッファの上限
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// 貯めておくバッファの数
+    pub slots: usize,
+    /// 貯めておくバッファの確保量の合計
+    pub max_bytes: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            slots: 16,
+            max_bytes: 256 << 20,
+        }
+    }
+}
+
+/// プールの使われ方 (上限の調整用)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// 貯めていたバッファを貸した回数
+    pub hits: u64,
+    /// 新しく確保した回数
+    pub misses: u64,
+    /// 上限を超えたので捨てたバッファの数
+    pub evicted: u64,
+    pub buffers_held: usize,
+    pub bytes_held: usize,
+}
 
 /// ヒープ領域のメモリのプール
-/// 返ってきたメモリはチャネルのキューに貯められる
-#[derive(Debug)]
+/// 複製したものは同じプールを共有し，どのスレッドからでも借りられる
+/// 返ってきたメモリは確保量ごとに振り分けて貯める
+#[derive(Debug, Clone, Default)]
 pub struct BufPool {
-    tx: mpsc::SyncSender<Vec<u8>>,
-    rx: mpsc::Receiver<Vec<u8>>,
-    default_capacity: usize,
+    inner: Arc<Mutex<Inner>>,
 }
 
-impl Default for BufPool {
+#[derive(Debug)]
+struct Inner {
+    config: PoolConfig,
+    /// i番目は確保量が[2^i, 2^(i+1))のバッファ
+    classes: Vec<Vec<Vec<u8>>>,
+    /// 最後に返ってきたバッファの階級 (大きさが分からない時に使う)
+    recent: Option<usize>,
+    stats: PoolStats,
+}
+
+impl Default for Inner {
     fn default() -> Self {
-        let (tx, rx) = mpsc::sync_channel(Self::BUFFER_SIZE);
+        Self::new(PoolConfig::default())
+    }
+}
+
+/// 確保量の2のべき乗の階級
+fn class_of(capacity: usize) -> usize {
+    (usize::BITS - 1 - capacity.max(1).leading_zeros()) as usize
+}
+
+impl Inner {
+    /// 要求より何階級大きいバッファまで貸すか
+    const OVERSIZE_CLASSES: usize = 2;
+    fn new(config: PoolConfig) -> Self {
         Self {
-            tx,
-            rx,
-            default_capacity: 0,
+            config,
+            classes: vec![vec![]; usize::BITS as usize],
+            recent: None,
+            stats: PoolStats::default(),
+        }
+    }
+    fn take(&mut self, capacity: Option<usize>) -> Option<Vec<u8>> {
+        let buf = match capacity {
+            None => self.recent.and_then(|c| self.classes[c].pop()),
+            Some(capacity) => self
+                .classes
+                .iter_mut()
+                .skip(class_of(capacity))
+                .take(Self::OVERSIZE_CLASSES + 1)
+                .find_map(|bufs| {
+                    let i = bufs.iter().position(|b| b.capacity() >= capacity)?;
+                    Some(bufs.swap_remove(i))
+                }),
+        };
+        match &buf {
+            Some(buf) => {
+                self.stats.hits += 1;
+                self.stats.buffers_held -= 1;
+                self.stats.bytes_held -= buf.capacity();
+            }
+            None => self.stats.misses += 1,
+        }
+        buf
+    }
+    /// 上限を超えたら大きいものから捨てる
+    fn put(&mut self, buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        let class = class_of(buf.capacity());
+        self.recent = Some(class);
+        self.stats.buffers_held += 1;
+        self.stats.bytes_held += buf.capacity();
+        self.classes[class].push(buf);
+        while self.stats.buffers_held > self.config.slots
+            || self.stats.bytes_held > self.config.max_bytes
+        {
+            let largest = self.classes.iter_mut().rev().find(|b| !b.is_empty());
+            let buf = largest.and_then(|b| b.pop()).expect("held buffers exist");
+            self.stats.evicted += 1;
+            self.stats.buffers_held -= 1;
+            self.stats.bytes_held -= buf.capacity();
         }
     }
 }
 
 impl BufPool {
-    pub const BUFFER_SIZE: usize = 1 << 2;
-    pub fn get_buffer(&mut self) -> Buffer {
-        use mpsc::TryRecvError::*;
-        let buf = match self.rx.try_recv() {
-            Ok(recved) => {
-                let cap = recved.capacity();
-                if cap > self.default_capacity {
-                    self.default_capacity = cap;
-                }
-                recved
-            }
-            Err(Empty) => Vec::with_capacity(self.default_capacity),
-            _ => panic!("Sender must has self"),
-        };
-        Buffer::new(buf, self.tx.clone())
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner::new(config))),
+        }
+    }
+    /// 大きさが分からない時は最後に返ってきたものと同じくらいのバッファを貸す
+    pub fn get_buffer(&self) -> Buffer {
+        let buf = self.lock().take(None);
+        self.add_buffer(buf.unwrap_or_default())
+    }
+    /// capacity以上の確保量のバッファを貸す
+    /// 要求よりずっと大きいバッファは貸さない
+    pub fn get_buffer_with_capacity(&self, capacity: usize) -> Buffer {
+        // 確保はロックの外で行う
+        let buf = self.lock().take(Some(capacity));
+        self.add_buffer(buf.unwrap_or_else(|| Vec::with_capacity(capacity)))
+    }
+    pub fn stats(&self) -> PoolStats {
+        self.lock().stats
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // 統計の途中でパニックしても貯めているバッファは壊れない
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
     }
     /// 外で確保したメモリをライフサイクルに組み込む
     pub fn add_buffer(&self, buf: Vec<u8>) -> Buffer {
-        Buffer::new(buf, self.tx.clone())
+        Buffer {
+            data: Data::Heap(buf),
+            pool: Arc::downgrade(&self.inner),
+        }
+    }
+    /// mmapした領域の一部をコピーせずにBufferとして扱う
+    pub fn map(&self, map: Arc<Mmap>, range: Range<usize>) -> Buffer {
+        Buffer {
+            data: Data::Mapped(map, range),
+            pool: Arc::downgrade(&self.inner),
+        }
     }
 }
 
+enum Data {
+    Heap(Vec<u8>),
+    Mapped(Arc<Mmap>, Range<usize>),
+    /// (ペイロード, 内容) の並び
+    Parts(Vec<(usize, Buffer)>),
+}
+
+/// プールから借りたメモリ，またはmmapした領域
+/// どちらも&[u8]として読める
+/// 複数の読み込み元の内容をコピーせずにまとめたもの (Joined) はJoined::partsでしか読めない (直接読むとパニックする)
+/// 破棄するとメモリはプールに返る (プールが先に破棄されていれば解放する)
 pub struct Buffer {
-    buf: Vec<u8>,
-    tx: mpsc::SyncSender<Vec<u8>>,
+    data: Data,
+    pool: Weak<Mutex<Inner>>,
 }
 
 impl std::fmt::Debug for Buffer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Buffer")
             .field("buf", &"buffer")
-            .field("tx", &self.tx)
+            .field("mapped", &self.is_mapped())
+            .field("parts", &self.parts().len())
             .finish()
     }
 }
 
 impl Buffer {
-    pub fn new(buf: Vec<u8>, tx: mpsc::SyncSender<Vec<u8>>) -> Self {
-        Self { buf, tx }
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.data, Data::Mapped(..))
+    }
+    /// mmapした領域に置き換える
+    /// 借りていたメモリはプールに返す
+    pub fn into_mapped(self, map: Arc<Mmap>, range: Range<usize>) -> Self {
+        let pool = self.pool.clone();
+        drop(self);
+        Self {
+            data: Data::Mapped(map, range),
+            pool,
+        }
+    }
+    /// 部分をまとめる
+    pub(crate) fn from_parts(parts: Vec<(usize, Buffer)>) -> Self {
+        Self {
+            data: Data::Parts(parts),
+            pool: Weak::new(),
+        }
+    }
+    /// まとめた部分 (まとめたものでなければ空)
+    pub(crate) fn parts(&self) -> &[(usize, Buffer)] {
+        match &self.data {
+            Data::Parts(parts) => parts,
+            _ => &[],
+        }
+    }
+    pub(crate) fn into_parts(mut self) -> Option<Vec<(usize, Buffer)>> {
+        match std::mem::replace(&mut self.data, Data::Heap(vec![])) {
+            Data::Parts(parts) => Some(parts),
+            data => {
+                self.data = data;
+                None
+            }
+        }
+    }
+    /// ヒープ上のメモリの確保量 (mmapした領域は長さ，まとめたものは部分の合計)
+    pub fn capacity(&self) -> usize {
+        match &self.data {
+            Data::Heap(v) => v.capacity(),
+            Data::Mapped(_, range) => range.len(),
+            Data::Parts(parts) => parts.iter().map(|(_, b)| b.capacity()).sum(),
+        }
+    }
+    /// 読み込んだ量 (まとめたものは部分の合計)
+    pub fn bytes(&self) -> usize {
+        match &self.data {
+            Data::Parts(parts) => parts.iter().map(|(_, b)| b.bytes()).sum(),
+            _ => self.len(),
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            Data::Heap(v) => v,
+            Data::Mapped(map, range) => &map[range.clone()],
+            Data::Parts(_) => panic!("joined buffer must be read with Joined::parts"),
+        }
     }
 }
 
-impl AsRef<Vec<u8>> for Buffer {
-    fn as_ref(&self) -> &Vec<u8> {
-        self.buf.as_ref()
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        self
     }
 }
 
+/// mmapした領域はヒープにコピーしてから書き換える
 impl AsMut<Vec<u8>> for Buffer {
     fn as_mut(&mut self) -> &mut Vec<u8> {
-        self.buf.as_mut()
+        match &self.data {
+            Data::Heap(_) => {}
+            Data::Mapped(map, range) => self.data = Data::Heap(map[range.clone()].to_vec()),
+            Data::Parts(_) => panic!("joined buffer must be read with Joined::parts"),
+        }
+        match &mut self.data {
+            Data::Heap(v) => v,
+            _ => unreachable!(),
+        }
     }
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        let mut buf = Vec::new(); //NOTE: no allocation
-        std::mem::swap(&mut self.buf, &mut buf);
-        buf.clear();
-        let _ = self.tx.try_send(buf);
+        if let Data::Heap(buf) = &mut self.data {
+            let mut buf = std::mem::take(buf); //NOTE: no allocation
+            buf.clear();
+            if let Some(pool) = self.pool.upgrade() {
+                pool.lock().unwrap_or_else(|e| e.into_inner()).put(buf);
+            }
+        }
     }
 }
 
@@ -94,7 +293,7 @@ mod tests {
     #[test]
     fn test_pool() {
         use super::*;
-        let mut pool = BufPool::default();
+        let pool = BufPool::default();
         {
             let mut x = pool.get_buffer();
             let xx = x.as_mut();
@@ -102,9 +301,122 @@ mod tests {
             xx.push(2);
         }
         let y = pool.get_buffer();
-        let v = y.as_ref();
-        dbg!(v.capacity());
-        assert_eq!(v.len(), 0);
-        assert_ne!(v.capacity(), 0);
+        dbg!(y.capacity());
+        assert_eq!(y.len(), 0);
+        assert_ne!(y.capacity(), 0);
+    }
+    #[test]
+    fn test_size_class() {
+        use super::*;
+        let pool = BufPool::default();
+        drop(pool.get_buffer_with_capacity(100));
+        drop(pool.get_buffer_with_capacity(1 << 20));
+        // 小さい要求に大きいバッファは貸さない
+        let small = pool.get_buffer_with_capacity(50);
+        assert!(small.capacity() >= 100 && small.capacity() < 1 << 20);
+        let large = pool.get_buffer_with_capacity(1000 << 10);
+        assert!(large.capacity() >= 1 << 20);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 2,
+                misses: 2,
+                ..Default::default()
+            }
+        );
+        // 返したものより大きい要求は新しく確保する
+        drop(small);
+        let x = pool.get_buffer_with_capacity(200);
+        assert!(x.capacity() >= 200);
+        let stats = pool.stats();
+        assert_eq!((stats.misses, stats.buffers_held), (3, 1));
+    }
+    #[test]
+    fn test_budget() {
+        use super::*;
+        let pool = BufPool::new(PoolConfig {
+            slots: 4,
+            max_bytes: 1200,
+        });
+        let bufs: Vec<_> = (0..3).map(|_| pool.get_buffer_with_capacity(500)).collect();
+        drop(bufs);
+        let stats = pool.stats();
+        assert_eq!((stats.buffers_held, stats.bytes_held), (2, 1000));
+        assert_eq!(stats.evicted, 1);
+        // 予算を超えたら大きいものから捨てる
+        drop(pool.get_buffer_with_capacity(2000));
+        let stats = pool.stats();
+        assert_eq!((stats.buffers_held, stats.bytes_held), (2, 1000));
+        assert_eq!(stats.evicted, 2);
+        assert_eq!(pool.get_buffer_with_capacity(500).capacity(), 500);
+    }
+    #[test]
+    fn test_concurrent() {
+        use super::*;
+        use std::sync::mpsc;
+        use std::thread;
+        fn assert_sync<T: Send + Sync>(_: &T) {}
+        let pool = BufPool::new(PoolConfig {
+            slots: 8,
+            max_bytes: 1 << 16,
+        });
+        assert_sync(&pool);
+        // 借りたスレッドとは別のスレッドで返す
+        let (tx, rx) = mpsc::channel::<Buffer>();
+        let dropper = thread::spawn(move || rx.into_iter().for_each(drop));
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let (pool, tx) = (pool.clone(), tx.clone());
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        let size = 1 << ((t + i) % 14);
+                        let mut buf = pool.get_buffer_with_capacity(size);
+                        assert!(buf.is_empty() && buf.capacity() >= size);
+                        buf.as_mut().resize(size, t as u8);
+                        assert!(buf.iter().all(|&b| b == t as u8));
+                        if i % 2 == 0 {
+                            tx.send(buf).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        drop(tx);
+        dropper.join().unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.hits + stats.misses, 8000);
+        assert!(stats.hits > 0);
+        assert!(stats.buffers_held <= 8 && stats.bytes_held <= 1 << 16);
+        let inner = pool.lock();
+        let held: Vec<_> = inner.classes.iter().flatten().collect();
+        assert_eq!(held.len(), stats.buffers_held);
+        assert_eq!(
+            held.iter().map(|b| b.capacity()).sum::<usize>(),
+            stats.bytes_held
+        );
+    }
+    #[test]
+    fn test_mapped() {
+        use super::*;
+        let path = std::env::temp_dir().join(format!("asyncfileio_mmap_{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let map = Arc::new(Mmap::open(&path).unwrap());
+        let pool = BufPool::default();
+        let mut x = pool.map(Arc::clone(&map), 2..6);
+        assert!(x.is_mapped());
+        assert_eq!(&x[..], b"2345");
+        // 書き換えるとコピーされる
+        x.as_mut().push(b'!');
+        assert!(!x.is_mapped());
+        assert_eq!(&x[..], b"2345!");
+        assert_eq!(&map[..], b"0123456789");
+        // コピーしたメモリはプールに返る
+        drop(x);
+        assert_ne!(pool.get_buffer().capacity(), 0);
+        assert!(Mmap::open(&path).is_ok());
+        std::fs::write(&path, b"").unwrap();
+        assert!(Mmap::open(&path).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
     }
 }
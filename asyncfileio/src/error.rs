@@ -0,0 +1,63 @@
+use std::{fmt, io};
+
+/// 読み込みに失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    Permission,
+    /// 読み込めたがデコーダが失敗した
+    Decode,
+    Other,
+}
+
+/// 読み込みに失敗したフレーム
+#[derive(Debug)]
+pub struct ReadError {
+    pub index: usize,
+    /// 表示用のフレーム名
+    pub path: String,
+    pub kind: ErrorKind,
+    pub source: anyhow::Error,
+}
+
+impl ReadError {
+    /// ファイルの読み込みで失敗した
+    /// io::Errorの種類から理由を決める
+    pub(crate) fn read(index: usize, path: String, source: anyhow::Error) -> Self {
+        let kind = match source.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::NotFound) => ErrorKind::NotFound,
+            Some(io::ErrorKind::PermissionDenied) => ErrorKind::Permission,
+            _ => ErrorKind::Other,
+        };
+        Self {
+            index,
+            path,
+            kind,
+            source,
+        }
+    }
+    pub(crate) fn decode(index: usize, path: String, source: anyhow::Error) -> Self {
+        Self {
+            index,
+            path,
+            kind: ErrorKind::Decode,
+            source,
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame {} ({}): {:?}: {}",
+            self.index, self.path, self.kind, self.source
+        )
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
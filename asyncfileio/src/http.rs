@@ -0,0 +1,407 @@
+use crate::{Buffer, FrameSource};
+use anyhow::{bail, ensure, Context, Result};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// HTTPサーバ上の連番
+/// フレームごとにGETし，範囲が決まっている場合はRangeで一部だけを取得する
+#[derive(Debug)]
+pub struct HttpSource {
+    frames: Vec<(String, Option<Range<u64>>)>,
+    /// 同時に送るリクエストの上限
+    slots: Slots,
+    /// 接続の失敗や5xxを受けた時に再試行する回数
+    retries: u32,
+    timeout: Duration,
+}
+
+impl HttpSource {
+    /// 1フレーム1URL
+    pub fn new(urls: Vec<String>) -> Self {
+        Self::from_frames(urls.into_iter().map(|url| (url, None)).collect())
+    }
+    /// 1つのファイルの中のバイト範囲をそれぞれフレームにする
+    pub fn ranges(url: &str, ranges: Vec<Range<u64>>) -> Self {
+        Self::from_frames(
+            ranges
+                .into_iter()
+                .map(|r| (url.to_owned(), Some(r)))
+                .collect(),
+        )
+    }
+    fn from_frames(frames: Vec<(String, Option<Range<u64>>)>) -> Self {
+        Self {
+            frames,
+            slots: Slots::new(4),
+            retries: 3,
+            timeout: Duration::from_secs(10),
+        }
+    }
+    pub fn concurrency(self, n: usize) -> Self {
+        Self {
+            slots: Slots::new(n.max(1)),
+            ..self
+        }
+    }
+    pub fn retries(self, n: u32) -> Self {
+        Self { retries: n, ..self }
+    }
+    /// 接続と読み込みのタイムアウト
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+    /// 1回のリクエスト
+    /// 再試行してよい失敗はio::Errorとして返す
+    fn get(&self, url: &str, range: Option<&Range<u64>>, buf: &mut Vec<u8>) -> Result<()> {
+        if let Some(r) = range {
+            ensure!(r.start < r.end, "empty range {:?}: {}", r, url);
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .with_context(|| format!("unsupported url: {}", url))?;
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let path = if path.is_empty() { "/" } else { path };
+        let addr = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+        let mut stream = connect(&addr, self.timeout)?;
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            path, host
+        );
+        if let Some(r) = range {
+            request += &format!("Range: bytes={}-{}\r\n", r.start, r.end - 1);
+        }
+        request += "\r\n";
+        stream.write_all(request.as_bytes())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status: u16 = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .with_context(|| format!("broken http response: {:?}", line))?;
+        let status_line = line.trim_end().to_owned();
+        let (mut len, mut chunked) = (None, false);
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').unwrap_or((header, ""));
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.parse::<u64>().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+        match status {
+            200 | 206 => {}
+            404 | 410 => return Err(io::Error::new(io::ErrorKind::NotFound, url.to_owned()).into()),
+            401 | 403 => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, url.to_owned()).into())
+            }
+            500..=599 => {
+                let e = format!("{}: {}", url, status_line);
+                return Err(io::Error::other(e).into());
+            }
+            _ => bail!("unexpected http status {}: {}", status, url),
+        }
+        let start = buf.len();
+        if chunked {
+            read_chunked(&mut reader, buf)?;
+        } else if let Some(len) = len {
+            buf.reserve(len as usize);
+            let read = reader.by_ref().take(len).read_to_end(buf)?;
+            if read as u64 != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        } else {
+            reader.read_to_end(buf)?;
+        }
+        // Rangeを無視して全体を返すサーバもある
+        if let (Some(r), 200) = (range, status) {
+            ensure!(
+                start + r.end as usize <= buf.len(),
+                "{} is shorter than {:?}",
+                url,
+                r
+            );
+            buf.drain(start..start + r.start as usize);
+            buf.truncate(start + (r.end - r.start) as usize);
+        }
+        Ok(())
+    }
+}
+
+impl FrameSource for HttpSource {
+    fn len(&self) -> usize {
+        self.frames.len()
+    }
+    fn name(&self, index: usize) -> String {
+        match &self.frames[index] {
+            (url, Some(r)) => format!("{}#{}-{}", url, r.start, r.end),
+            (url, None) => url.clone(),
+        }
+    }
+    fn size_hint(&self, index: usize) -> Option<usize> {
+        let (_, range) = &self.frames[index];
+        range
+            .as_ref()
+            .map(|r| r.end.saturating_sub(r.start) as usize)
+    }
+    fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+        let (url, range) = &self.frames[index];
+        let _slot = self.slots.acquire();
+        let mut retry = 0;
+        loop {
+            buf.as_mut().clear();
+            match self.get(url, range.as_ref(), buf.as_mut()) {
+                Ok(()) => return Ok(buf),
+                Err(e) if retry < self.retries && retryable(&e) => {
+                    thread::sleep(Duration::from_millis(10 << retry));
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    use std::net::ToSocketAddrs;
+    let mut last = io::Error::new(io::ErrorKind::NotFound, addr.to_owned());
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))?;
+                return Ok(s);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// 接続の失敗やサーバ側のエラーは時間を置けば成功するかもしれない
+fn retryable(e: &anyhow::Error) -> bool {
+    use io::ErrorKind::*;
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => !matches!(e.kind(), NotFound | PermissionDenied),
+        None => false,
+    }
+}
+
+fn read_chunked(reader: &mut impl BufRead, buf: &mut Vec<u8>) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let size = line.trim_end().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "broken chunk"))?;
+        if size == 0 {
+            return Ok(());
+        }
+        let start = buf.len();
+        buf.resize(start + size, 0);
+        reader.read_exact(&mut buf[start..])?;
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+}
+
+/// 数を数えるだけのセマフォ
+#[derive(Debug)]
+struct Slots {
+    free: Mutex<usize>,
+    cond: Condvar,
+}
+
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    fn new(n: usize) -> Self {
+        Self {
+            free: Mutex::new(n),
+            cond: Condvar::new(),
+        }
+    }
+    fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.cond.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.cond.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BufPool;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// テスト用のHTTPサーバ
+    /// /flakyは1回おきに503を返し，/fullはRangeを無視する
+    struct Server {
+        url: String,
+        /// 同時に処理したリクエストの最大数
+        max_active: Arc<AtomicUsize>,
+    }
+
+    impl Server {
+        fn spawn(files: HashMap<&'static str, Vec<u8>>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let active = Arc::new(AtomicUsize::new(0));
+            let max_active = Arc::new(AtomicUsize::new(0));
+            let files = Arc::new(files);
+            let flaky = Arc::new(AtomicUsize::new(0));
+            let (a, m) = (Arc::clone(&active), Arc::clone(&max_active));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let (files, a, m, flaky) = (
+                        Arc::clone(&files),
+                        Arc::clone(&a),
+                        Arc::clone(&m),
+                        Arc::clone(&flaky),
+                    );
+                    thread::spawn(move || {
+                        let n = a.fetch_add(1, Ordering::SeqCst) + 1;
+                        m.fetch_max(n, Ordering::SeqCst);
+                        let mut stream = stream.unwrap();
+                        let res = Self::respond(&stream, &files, &flaky);
+                        // クライアントが応答を読み終える前に数え終える
+                        a.fetch_sub(1, Ordering::SeqCst);
+                        let _ = stream.write_all(&res.unwrap_or_default());
+                    });
+                }
+            });
+            Self { url, max_active }
+        }
+        /// 応答を作る
+        fn respond(
+            stream: &TcpStream,
+            files: &HashMap<&str, Vec<u8>>,
+            flaky: &AtomicUsize,
+        ) -> io::Result<Vec<u8>> {
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let path = line.split_whitespace().nth(1).unwrap_or("/").to_owned();
+            let mut range = None;
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(r) = line.trim_end().strip_prefix("Range: bytes=") {
+                    let (a, b) = r.split_once('-').unwrap();
+                    range = Some(a.parse::<usize>().unwrap()..b.parse::<usize>().unwrap() + 1);
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+            if path == "/flaky" && flaky.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+                return Ok(b"HTTP/1.1 503 Busy\r\nContent-Length: 0\r\n\r\n".to_vec());
+            }
+            let body = match files.get(&path[1..]) {
+                Some(body) => body,
+                None => return Ok(b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec()),
+            };
+            let (status, body) = match range {
+                Some(r) if path != "/full" => ("206 Partial Content", &body[r]),
+                _ => ("200 OK", &body[..]),
+            };
+            let mut res = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                status,
+                body.len()
+            )
+            .into_bytes();
+            res.extend_from_slice(body);
+            Ok(res)
+        }
+    }
+
+    #[test]
+    fn test_http() {
+        let files = HashMap::from([
+            ("0", b"zero".to_vec()),
+            ("flaky", b"ok".to_vec()),
+            ("full", b"0123456789".to_vec()),
+        ]);
+        let server = Server::spawn(files);
+        let pool = BufPool::default();
+        let read = |source: &HttpSource, i| {
+            source
+                .read(i, pool.get_buffer())
+                .map(|b| String::from_utf8(b.to_vec()).unwrap())
+        };
+        let urls = ["0", "flaky", "missing"]
+            .iter()
+            .map(|p| format!("{}/{}", server.url, p))
+            .collect();
+        let source = HttpSource::new(urls);
+        assert_eq!(read(&source, 0).unwrap(), "zero");
+        // 503の後に再試行する
+        assert_eq!(read(&source, 1).unwrap(), "ok");
+        let e = read(&source, 2).unwrap_err();
+        let e = crate::ReadError::read(2, source.name(2), e);
+        assert_eq!(e.kind, crate::ErrorKind::NotFound);
+        assert!(read(&source.retries(0), 1).is_err());
+
+        let source = HttpSource::ranges(&format!("{}/full", server.url), vec![2..5, 7..10]);
+        assert_eq!(source.size_hint(0), Some(3));
+        assert_eq!(read(&source, 0).unwrap(), "234");
+        assert_eq!(read(&source, 1).unwrap(), "789");
+        // 空の範囲は要求しない
+        let source = HttpSource::ranges(&format!("{}/full", server.url), vec![2..5, 4..4]);
+        assert!(read(&source, 1).is_err());
+    }
+
+    #[test]
+    fn test_http_concurrency() {
+        let files = HashMap::from([("data", (0..=255).collect())]);
+        let server = Server::spawn(files);
+        let ranges = (0..16).map(|i| i * 16..i * 16 + 16).collect();
+        let source =
+            Arc::new(HttpSource::ranges(&format!("{}/data", server.url), ranges).concurrency(2));
+        let pool = BufPool::default();
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let (source, pool) = (Arc::clone(&source), pool.clone());
+                thread::spawn(move || {
+                    let buf = pool.get_buffer();
+                    let buf = source.read(i, buf).unwrap();
+                    assert_eq!(buf[0] as usize, i * 16);
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(server.max_active.load(Ordering::SeqCst), 2);
+    }
+}
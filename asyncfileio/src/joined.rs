@@ -0,0 +1,238 @@
+use crate::{BufPool, Buffer, Change, FrameSource, Notify, Source};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// 同じフレーム番号の複数のストリーム (頂点とテクスチャなど) をまとめて1つのフレームとして読む
+/// 全てのストリームが揃ったフレームだけを数え，1つの読み込みスレッドで同じ再生位置から先読みする
+/// 読んだ内容はコピーせずにまとめるので，partsで各ストリームのBufferに分けてから読む
+#[derive(Debug)]
+pub struct Joined {
+    streams: Vec<Source>,
+    /// ストリームごとのペイロードの組 -> まとめたペイロード
+    payloads: Mutex<Payloads>,
+    pool: BufPool,
+}
+
+#[derive(Debug, Default)]
+struct Payloads {
+    of: Vec<usize>,
+    ids: HashMap<Vec<usize>, usize>,
+}
+
+/// まとめて読んだフレームのうち1つのストリームの部分
+#[derive(Debug)]
+pub struct Part {
+    /// ストリームでのペイロード (同じなら内容も同じ)
+    pub payload: usize,
+    /// ストリームが読んだまま (mmapした領域ならそのまま)
+    pub buf: Buffer,
+}
+
+impl Joined {
+    pub fn new(streams: Vec<Source>) -> Self {
+        Self {
+            streams,
+            payloads: Mutex::default(),
+            pool: BufPool::default(),
+        }
+    }
+    pub fn streams(&self) -> &[Source] {
+        &self.streams
+    }
+    /// readで読んだ内容をストリームごとの部分に分ける
+    pub fn parts(buf: Buffer) -> Result<Vec<Part>> {
+        let parts = buf.into_parts().context("not a joined frame")?;
+        Ok(parts
+            .into_iter()
+            .map(|(payload, buf)| Part { payload, buf })
+            .collect())
+    }
+}
+
+impl FrameSource for Joined {
+    /// 全てのストリームが揃っているフレーム数
+    fn len(&self) -> usize {
+        self.streams.iter().map(|s| s.len()).min().unwrap_or(0)
+    }
+    fn name(&self, index: usize) -> String {
+        let names: Vec<_> = self.streams.iter().map(|s| s.name(index)).collect();
+        names.join(" + ")
+    }
+    /// 全てのストリームで同じ内容のフレームに同じ番号を振る
+    fn payload_of(&self, index: usize) -> usize {
+        let mut p = self.payloads.lock().unwrap();
+        while p.of.len() <= index {
+            let i = p.of.len();
+            let key: Vec<_> = self.streams.iter().map(|s| s.payload_of(i)).collect();
+            let next = p.ids.len();
+            let id = *p.ids.entry(key).or_insert(next);
+            p.of.push(id);
+        }
+        p.of[index]
+    }
+    /// 先頭のストリームは渡されたバッファに読む
+    fn size_hint(&self, index: usize) -> Option<usize> {
+        self.streams.first()?.size_hint(index)
+    }
+    /// 全てのストリームが揃った時に伸ばし，どれかが書き換わったら通知する
+    fn watch(&self, notify: Notify) {
+        let lens: Vec<_> = self.streams.iter().map(|s| s.len()).collect();
+        let shared = Arc::new(Mutex::new((lens, notify)));
+        for (k, stream) in self.streams.iter().enumerate() {
+            let shared = Arc::clone(&shared);
+            stream.watch(Box::new(move |change| {
+                let (lens, notify) = &mut *shared.lock().unwrap();
+                let before = lens.iter().copied().min().unwrap_or(0);
+                match change {
+                    Change::Added { len } => {
+                        lens[k] = len;
+                        let len = lens.iter().copied().min().unwrap_or(0);
+                        if len != before {
+                            notify(Change::Added { len });
+                        }
+                    }
+                    Change::Modified(i) if i < before => notify(change),
+                    Change::Modified(_) => {}
+                }
+            }));
+        }
+    }
+    fn read(&self, index: usize, buf: Buffer) -> Result<Buffer> {
+        let mut buf = Some(buf);
+        let parts = self
+            .streams
+            .iter()
+            .map(|s| {
+                let part = match (buf.take(), s.size_hint(index)) {
+                    (Some(buf), _) => buf,
+                    (None, Some(len)) => self.pool.get_buffer_with_capacity(len),
+                    (None, None) => self.pool.get_buffer(),
+                };
+                Ok((s.payload_of(index), s.read(index, part)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Buffer::from_parts(parts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// 手で伸ばしたり書き換えたりできる読み込み元
+    /// 内容は"名前:ペイロード"
+    #[derive(Default)]
+    struct Fake {
+        name: &'static str,
+        len: Mutex<usize>,
+        /// 何フレームごとに同じ内容か
+        hold: usize,
+        subscribers: Mutex<Vec<Notify>>,
+    }
+
+    impl std::fmt::Debug for Fake {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Fake").field("name", &self.name).finish()
+        }
+    }
+
+    impl Fake {
+        fn new(name: &'static str, len: usize, hold: usize) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                len: Mutex::new(len),
+                hold,
+                ..Default::default()
+            })
+        }
+        fn notify(&self, change: Change) {
+            if let Change::Added { len } = change {
+                *self.len.lock().unwrap() = len;
+            }
+            self.subscribers
+                .lock()
+                .unwrap()
+                .iter()
+                .for_each(|f| f(change));
+        }
+    }
+
+    impl FrameSource for Fake {
+        fn len(&self) -> usize {
+            *self.len.lock().unwrap()
+        }
+        fn name(&self, index: usize) -> String {
+            format!("{}{}", self.name, index)
+        }
+        fn payload_of(&self, index: usize) -> usize {
+            index / self.hold
+        }
+        fn size_hint(&self, index: usize) -> Option<usize> {
+            Some(self.name.len() + 1 + self.payload_of(index).to_string().len())
+        }
+        fn watch(&self, notify: Notify) {
+            self.subscribers.lock().unwrap().push(notify);
+        }
+        fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+            let s = format!("{}:{}", self.name, self.payload_of(index));
+            buf.as_mut().extend_from_slice(s.as_bytes());
+            Ok(buf)
+        }
+    }
+
+    #[test]
+    fn test_joined() {
+        let (mesh, tex) = (Fake::new("mesh", 6, 1), Fake::new("texture", 4, 2));
+        let joined = Joined::new(vec![Source::from(mesh), Source::from(tex)]);
+        assert_eq!(joined.len(), 4);
+        assert_eq!(joined.name(1), "mesh1 + texture1");
+        let pool = BufPool::default();
+        let buf = joined.read(3, pool.get_buffer()).unwrap();
+        assert_eq!(buf.bytes(), 15);
+        // partsを通さずに読むと空ではなくパニックする (読み込みスレッドではデコードの失敗になる)
+        let direct = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| buf.len()));
+        assert!(direct.is_err());
+        let parts = Joined::parts(buf).unwrap();
+        let text: Vec<_> = parts.iter().map(|p| &p.buf[..]).collect();
+        assert_eq!(text, [&b"mesh:3"[..], b"texture:1"]);
+        assert_eq!(parts[1].payload, 1);
+        assert!(Joined::parts(pool.get_buffer()).is_err());
+    }
+
+    #[test]
+    fn test_joined_payload() {
+        // どちらのストリームも同じ内容のフレームだけを同じペイロードにする
+        let joined = Joined::new(vec![
+            Source::from(Fake::new("a", 8, 2)),
+            Source::from(Fake::new("b", 8, 4)),
+        ]);
+        let payloads: Vec<_> = (0..8).rev().map(|i| joined.payload_of(i)).collect();
+        assert_eq!(payloads, [3, 3, 2, 2, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_joined_watch() {
+        let (mesh, tex) = (Fake::new("mesh", 3, 1), Fake::new("texture", 2, 1));
+        let joined = Joined::new(vec![Source::from(mesh.clone()), Source::from(tex.clone())]);
+        let (tx, rx) = mpsc::channel();
+        joined.watch(Box::new(move |c| tx.send(c).unwrap()));
+        // 全てのストリームが揃うまで伸ばさない
+        mesh.notify(Change::Added { len: 5 });
+        tex.notify(Change::Added { len: 4 });
+        tex.notify(Change::Added { len: 6 });
+        mesh.notify(Change::Modified(1));
+        tex.notify(Change::Modified(5));
+        let changes: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            changes,
+            [
+                Change::Added { len: 4 },
+                Change::Added { len: 5 },
+                Change::Modified(1)
+            ]
+        );
+        assert_eq!(joined.len(), 5);
+    }
+}
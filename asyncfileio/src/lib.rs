@@ -1,131 +1,437 @@
 use anyhow::{anyhow, Result};
+pub use std::sync::mpsc::RecvTimeoutError;
 use std::{
-    collections::VecDeque,
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{atomic::Ordering, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 pub use tokio::sync::mpsc::error::TryRecvError;
 use tokio::{
     fs,
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::mpsc,
+    sync::{mpsc, Semaphore},
     task,
-    time::sleep,
 };
 use util::SliceAs;
 
+mod archive;
 mod bufmanager;
-pub use bufmanager::{BufPool, Buffer};
+mod error;
+mod http;
+mod joined;
+mod metrics;
+mod mmap;
+mod pack;
+mod playhead;
+mod scheduler;
+mod source;
+mod watch;
+pub use archive::Archive;
+pub use bufmanager::{BufPool, Buffer, PoolConfig, PoolStats};
+pub use error::{ErrorKind, ReadError};
+pub use http::HttpSource;
+pub use joined::{Joined, Part};
+use metrics::Recorder;
+pub use metrics::{Latency, Metrics};
+pub use mmap::Mmap;
+pub use pack::{Pack, PackStats, PackWriter};
+pub use playhead::{PlayMode, Playhead};
+use scheduler::Scheduler;
+pub use source::{Change, FrameSource, Notify, PathList, Source};
+pub use watch::WatchFolder;
 #[derive(Debug)]
 pub enum Msg {
     Reload(usize),
+    /// 1フレームにstepずつ進む (負なら逆再生)
     Step(isize),
+    /// 1未満なら同じフレームを読み直さずに繰り返す
+    Speed(f64),
+    /// 新しい読み込みを止める (読み込み中のものとSeek先は届く)
+    Pause,
+    Resume,
+    /// 次に届くフレームをindexにする
+    /// flushしない場合は先読み済みのフレームを使い回す
+    Seek {
+        index: usize,
+        flush: bool,
+    },
+    /// 端での振る舞いを変える
+    SetMode(PlayMode),
+    /// SetMode(PlayMode::LoopRange { start, end })と同じ
+    SetRange {
+        start: usize,
+        end: usize,
+    },
+    /// 先読みするフレーム数を変える
+    SetLookahead(usize),
+    /// 再生位置を変えずにindexだけを読んで届ける
+    /// 先読みとは別に読むので，Seekや先読みの取り消しに関わらず必ず届く
+    Fetch(usize),
     Terminate,
 }
-const SLEEP: u64 = 1;
 /// 非同期にファイルを読み込むスレッドを管理する
+/// 破棄すると読み込み中のものを取り消してスレッドの終了を待つ
 #[derive(Debug)]
 pub struct AsyncFileReader<T: 'static + Send> {
-    pub tx: mpsc::Sender<Msg>,
-    pub rx: mpsc::Receiver<Responce<T>>,
+    /// 制御メッセージは小さいので溜めておき，送信でブロックしない
+    tx: mpsc::UnboundedSender<Msg>,
+    rx: mpsc::Receiver<Tagged<T>>,
+    /// 送信したSeekの数 (これより古い応答は捨てる)
+    epoch: Cell<u64>,
+    metrics: Arc<Recorder>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// recv_timeoutで待つためのランタイム (初めて待つ時に作る)
+    waiter: Option<tokio::runtime::Runtime>,
+}
+/// 読み込みスレッドの設定
+#[derive(Debug, Clone, Copy)]
+pub struct ReaderConfig {
+    /// 再生位置から先読みするフレーム数
+    pub lookahead: usize,
+    /// 同時にデコードするフレーム数 (デコード用のスレッド数)
+    pub decode_workers: usize,
+    /// 同時に読み込むフレーム数
+    pub io_concurrency: usize,
+    /// 読み込み用のバッファのプール
+    pub pool: PoolConfig,
+}
+impl Default for ReaderConfig {
+    fn default() -> Self {
+        Self {
+            lookahead: 8,
+            decode_workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            io_concurrency: 4,
+            pool: PoolConfig::default(),
+        }
+    }
 }
 pub type Paths = Vec<String>;
-type Responce<T> = Result<(usize, T)>;
+type Responce<T> = Result<(usize, T), ReadError>;
+/// 何回目のSeekの後に読んだか
+type Tagged<T> = (u64, Responce<T>);
+/// Fetchで頼まれたものはSeekに関わらず届ける
+const FETCHED: u64 = u64::MAX;
+/// 読み込みとデコードの同時実行数
+struct Limits {
+    io: Arc<Semaphore>,
+    decode: Arc<Semaphore>,
+}
+
 impl<T: 'static + Send> AsyncFileReader<T> {
     const BUFFER_SIZE: usize = 1 << 3;
     /// コンストラクタ
     /// スレッドを一つ立ち上げる
-    pub fn spawn(paths: Arc<Paths>, decoder: fn(Buffer) -> T) -> Self {
-        let (tx, rx_th) = mpsc::channel(Self::BUFFER_SIZE);
+    /// decoderが失敗したフレームはErrorKind::Decodeとして届く
+    pub fn spawn(source: impl Into<Source>, decoder: fn(Buffer) -> Result<T>) -> Self {
+        Self::spawn_with(source, decoder, ReaderConfig::default())
+    }
+    pub fn spawn_with(
+        source: impl Into<Source>,
+        decoder: fn(Buffer) -> Result<T>,
+        config: ReaderConfig,
+    ) -> Self {
+        let source = source.into();
+        let (tx, rx_th) = mpsc::unbounded_channel();
         let (tx_th, rx) = mpsc::channel(Self::BUFFER_SIZE);
-        let _ = thread::spawn(move || Self::spawn_inner(paths, tx_th, rx_th, decoder));
-        Self { tx, rx }
-    }
-    async fn read_file(path: Arc<Paths>, index: usize, m: Arc<Mutex<BufPool>>) -> Responce<Buffer> {
-        let mut file = fs::File::open(&path[index]).await?;
-        let mut buf = m.lock().unwrap().get_buffer();
-        file.read_to_end(buf.as_mut()).await?;
-        Ok((index, buf))
-    }
-    /// 読み込んだファイルの内容を順番に外部に送信する
-    async fn pop_send(
-        tx: &mpsc::Sender<Responce<T>>,
-        handles: &Arc<Mutex<VecDeque<task::JoinHandle<Responce<T>>>>>,
-    ) -> task::JoinHandle<()> {
-        let tx = tx.clone();
-        let handles = Arc::clone(handles);
+        let pool = BufPool::new(config.pool);
+        let metrics = Arc::new(Recorder::new(Self::BUFFER_SIZE, pool.clone()));
+        let m = Arc::clone(&metrics);
+        let handle = thread::spawn(move || {
+            // 読み込みとデコードはどちらもブロッキングスレッドで行う
+            tokio::runtime::Builder::new_current_thread()
+                .max_blocking_threads(config.io_concurrency.max(1) + config.decode_workers.max(1))
+                .enable_all()
+                .build()
+                .expect("failed to build runtime")
+                .block_on(Self::spawn_inner(
+                    source, tx_th, rx_th, decoder, pool, config, m,
+                ))
+        });
+        Self {
+            tx,
+            rx,
+            epoch: Cell::new(0),
+            metrics,
+            handle: Some(handle),
+            waiter: None,
+        }
+    }
+    /// メッセージの送信
+    pub fn send(&self, msg: Msg) -> Result<()> {
+        if let Msg::Seek { .. } = msg {
+            self.epoch.set(self.epoch.get() + 1);
+        }
+        self.tx
+            .send(msg)
+            .map_err(|_| anyhow!("async file reader is terminated"))
+    }
+    /// 読み込み結果の受信
+    /// Seekより前に読まれたものは読み飛ばす
+    pub fn try_recv(&mut self) -> Result<Responce<T>, TryRecvError> {
+        loop {
+            let (epoch, res) = self.rx.try_recv()?;
+            self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            if epoch == self.epoch.get() || epoch == FETCHED {
+                return Ok(res);
+            }
+            self.metrics.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// 読み込み結果が届くまで待つ
+    /// 読み込みスレッドが終わっていればNone
+    pub async fn recv(&mut self) -> Option<Responce<T>> {
+        loop {
+            let (epoch, res) = self.rx.recv().await?;
+            self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            if epoch == self.epoch.get() || epoch == FETCHED {
+                return Some(res);
+            }
+            self.metrics.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// 読み込み結果が届くまで最大timeoutだけブロックする
+    /// 非同期の文脈からは呼ばずにrecvを使う
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Responce<T>, RecvTimeoutError> {
+        let rt = self.waiter.take().unwrap_or_else(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .expect("failed to build runtime")
+        });
+        let res = rt.block_on(async { tokio::time::timeout(timeout, self.recv()).await });
+        self.waiter = Some(rt);
+        match res {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(RecvTimeoutError::Disconnected),
+            Err(_) => Err(RecvTimeoutError::Timeout),
+        }
+    }
+    /// 読み込みスレッドの統計
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+    /// 読み込みとデコードを1つのタスクとして始める
+    /// FrameSource::readはブロッキングなので，どちらもブロッキングスレッドで行う
+    /// それぞれの同時実行数はセマフォで制限する
+    fn spawn_read(
+        source: &Source,
+        index: usize,
+        pool: &BufPool,
+        decoder: fn(Buffer) -> Result<T>,
+        limits: &Limits,
+        metrics: &Arc<Recorder>,
+    ) -> task::JoinHandle<Responce<T>> {
+        metrics.requested.fetch_add(1, Ordering::Relaxed);
+        let (r, r2) = (Arc::clone(metrics), Arc::clone(metrics));
+        let p = source.clone();
+        let pool = pool.clone();
+        let (io, decode) = (Arc::clone(&limits.io), Arc::clone(&limits.decode));
         task::spawn(async move {
-            loop {
-                sleep(Duration::from_micros(SLEEP)).await;
-                let poped = handles.lock().unwrap().pop_front();
-                // dbg!("pop sender get lock");
-                if let Some(handle) = poped {
-                    let handle: task::JoinHandle<Responce<T>> = handle;
-                    let x: Responce<T> = handle.await.unwrap();
-                    let _ = tx.send(x).await;
-                } else {
-                }
+            let permit = io.acquire_owned().await.expect("semaphore is never closed");
+            let q = p.clone();
+            let read = task::spawn_blocking(move || {
+                let _permit = permit;
+                let start = Instant::now();
+                let buf = match q.size_hint(index) {
+                    Some(len) => pool.get_buffer_with_capacity(len),
+                    None => pool.get_buffer(),
+                };
+                let buf = q.read(index, buf)?;
+                r.read(start.elapsed(), buf.bytes());
+                Ok(buf)
+            });
+            let buf = match read.await {
+                Ok(res) => res,
+                // 読み込み元のパニック
+                Err(e) => Err(e.into()),
+            }
+            .map_err(|e| ReadError::read(index, p.name(index), e))?;
+            let permit = decode
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            let decoded = task::spawn_blocking(move || {
+                let _permit = permit;
+                let start = Instant::now();
+                let res = decoder(buf);
+                r2.decode(start.elapsed());
+                res
+            });
+            match decoded.await {
+                Ok(Ok(res)) => Ok((index, res)),
+                Ok(Err(e)) => Err(ReadError::decode(index, p.name(index), e)),
+                // デコーダのパニック
+                Err(e) => Err(ReadError::decode(index, p.name(index), e.into())),
             }
         })
     }
-
-    #[tokio::main(flavor = "current_thread")]
+    /// 制御メッセージ，送信先の空き，先頭の読み込み完了のいずれかを待つ
+    /// 何も起きなければスレッドは眠ったままになる
     async fn spawn_inner(
-        paths: Arc<Paths>,
-        tx: mpsc::Sender<Responce<T>>,
-        mut rx: mpsc::Receiver<Msg>,
-        decoder: fn(Buffer) -> T,
+        source: Source,
+        tx: mpsc::Sender<Tagged<T>>,
+        mut rx: mpsc::UnboundedReceiver<Msg>,
+        decoder: fn(Buffer) -> Result<T>,
+        pool: BufPool,
+        config: ReaderConfig,
+        metrics: Arc<Recorder>,
     ) {
-        let mut index = 0;
-        let mut step = 1;
-        let manager = Arc::new(Mutex::new(BufPool::default()));
-        let handles = Arc::new(Mutex::new(VecDeque::new()));
-        let _ = Self::pop_send(&tx, &handles).await;
+        let payload_of = |i| source.payload_of(i);
+        let mut scheduler = Scheduler::new(source.len(), config.lookahead);
+        let mut epoch = 0;
+        let limits = Limits {
+            io: Arc::new(Semaphore::new(config.io_concurrency.max(1))),
+            decode: Arc::new(Semaphore::new(config.decode_workers.max(1))),
+        };
+        // ペイロード -> 読み込み中のフレームとタスク
+        let mut reading: HashMap<usize, (usize, task::JoinHandle<Responce<T>>)> = HashMap::new();
+        // Fetchで頼まれて再生位置とは別に読んでいるもの (頼まれた順に届ける)
+        let mut fetches: VecDeque<(usize, task::JoinHandle<Responce<T>>)> = VecDeque::new();
+        let (change_tx, mut changes) = mpsc::unbounded_channel();
+        source.watch(Box::new(move |c| {
+            let _ = change_tx.send(c);
+        }));
+        let cancel = |h: &task::JoinHandle<_>| {
+            h.abort();
+            metrics.cancelled.fetch_add(1, Ordering::Relaxed);
+        };
+        let count = |res: &Responce<T>| {
+            let counter = match res {
+                Ok(_) => &metrics.delivered,
+                Err(_) => &metrics.failed,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            metrics.queued.fetch_add(1, Ordering::Relaxed);
+        };
         loop {
-            sleep(Duration::from_micros(SLEEP)).await;
-            {
-                let mut handles = handles.lock().unwrap();
-                if handles.len() > Self::BUFFER_SIZE {
-                    continue;
+            let window = scheduler.window(payload_of);
+            // 再生位置から外れたものは取り消す
+            let wanted: HashSet<_> = window.iter().map(|(i, _)| payload_of(*i)).collect();
+            reading.retain(|payload, (_, h)| {
+                let keep = wanted.contains(payload);
+                if !keep {
+                    cancel(h);
                 }
-                let p = Arc::clone(&paths);
-                let m = Arc::clone(&manager);
-                let h = task::spawn(async move {
-                    let x = Self::read_file(p, index, m).await;
-                    match x {
-                        Ok((i, buf)) => {
-                            let res = task::spawn_blocking(move || decoder(buf)).await.unwrap();
-                            Ok((i, res))
-                        }
-                        Err(e) => Err(e),
-                    }
+                keep
+            });
+            // 近いものから読み始める
+            for (index, _) in window.iter().take(scheduler.reads()) {
+                reading.entry(payload_of(*index)).or_insert_with(|| {
+                    (
+                        *index,
+                        Self::spawn_read(&source, *index, &pool, decoder, &limits, &metrics),
+                    )
                 });
-                handles.push_back(h);
             }
+            metrics
+                .in_flight
+                .store(reading.len() + fetches.len(), Ordering::Relaxed);
+            let front = window
+                .first()
+                .filter(|(i, _)| reading.contains_key(&payload_of(*i)))
+                .cloned();
 
-            use mpsc::error::TryRecvError::*;
             use Msg::*;
-            //外部からの制御
-            match rx.try_recv() {
-                Ok(Reload(p)) => index = p,
-                Ok(Step(s)) => step = s,
-                Ok(Terminate) | Err(Disconnected) => break,
-                _ => {
-                    if step.is_positive() {
-                        index += step as usize;
-                    } else {
-                        if index == 0 {
-                            index = paths.len()
+            tokio::select! {
+                //外部からの制御
+                msg = rx.recv() => match msg {
+                    Some(Reload(p)) => scheduler.reload(p),
+                    Some(Step(s)) => scheduler.set_speed(s as f64),
+                    Some(Speed(s)) => scheduler.set_speed(s),
+                    Some(Pause) => scheduler.paused = true,
+                    Some(Resume) => scheduler.paused = false,
+                    Some(Seek { index, flush }) => {
+                        epoch += 1;
+                        if flush {
+                            reading.drain().for_each(|(_, (_, h))| cancel(&h));
                         }
-                        index -= step.abs() as usize;
+                        scheduler.seek(index, payload_of);
                     }
-                    index %= paths.len();
-                }
-            } //match
+                    Some(SetMode(mode)) => scheduler.set_mode(mode),
+                    Some(SetRange { start, end }) => {
+                        scheduler.set_mode(PlayMode::LoopRange { start, end })
+                    }
+                    Some(SetLookahead(n)) => scheduler.lookahead = n.max(1),
+                    Some(Fetch(index)) => {
+                        let payload = payload_of(index);
+                        if fetches.iter().all(|(i, _)| payload_of(*i) != payload) {
+                            let h = Self::spawn_read(&source, index, &pool, decoder, &limits, &metrics);
+                            fetches.push_back((index, h));
+                        }
+                    }
+                    Some(Terminate) | None => break,
+                },
+                // 読み込み元の変化 (変化しない読み込み元では来ない)
+                Some(change) = changes.recv() => match change {
+                    Change::Added { len } => scheduler.set_len(len),
+                    Change::Modified(index) => {
+                        let payload = payload_of(index);
+                        // 書き換え前の内容を読んでいるかもしれない
+                        if let Some((_, h)) = reading.remove(&payload) {
+                            cancel(&h);
+                        }
+                        scheduler.modified(payload);
+                    }
+                },
+                // 送信先に空きができてから先頭の完了を待つので，
+                // 途中で制御メッセージが来ても読み込んだ内容は失われない
+                sent = async {
+                    let (index, _) = front.as_ref().unwrap();
+                    let permit = tx.reserve().await?;
+                    let res = (&mut reading.get_mut(&payload_of(*index)).unwrap().1).await;
+                    Ok::<_, mpsc::error::SendError<()>>((permit, res))
+                }, if front.is_some() => match sent {
+                    Ok((permit, res)) => {
+                        let (_, at) = front.unwrap();
+                        let payload = payload_of(at.index());
+                        let (index, _) = reading.remove(&payload).unwrap();
+                        // タスクのパニック
+                        let res = res.unwrap_or_else(|e| {
+                            Err(ReadError::decode(index, source.name(index), e.into()))
+                        });
+                        scheduler.deliver(at, payload, res.is_ok());
+                        count(&res);
+                        permit.send((epoch, res));
+                    }
+                    // 受信側が破棄された
+                    Err(_) => break,
+                },
+                // Fetchで頼まれたものは再生位置を進めずに届ける
+                fetched = async {
+                    let permit = tx.reserve().await?;
+                    let res = (&mut fetches.front_mut().unwrap().1).await;
+                    Ok::<_, mpsc::error::SendError<()>>((permit, res))
+                }, if !fetches.is_empty() => match fetched {
+                    Ok((permit, res)) => {
+                        let (index, _) = fetches.pop_front().unwrap();
+                        // タスクのパニック (読み込みスレッドの終了時にしか取り消さない)
+                        let res = res.unwrap_or_else(|e| {
+                            Err(ReadError::decode(index, source.name(index), e.into()))
+                        });
+                        count(&res);
+                        permit.send((FETCHED, res));
+                    }
+                    Err(_) => break,
+                },
+            }
         } //loop
-        let _ = tx.send(Err(anyhow!("terminated"))).await;
+        reading.values().for_each(|(_, h)| h.abort());
+        fetches.iter().for_each(|(_, h)| h.abort());
+    }
+}
+
+impl<T: 'static + Send> Drop for AsyncFileReader<T> {
+    fn drop(&mut self) {
+        // 送信側を閉じると読み込みスレッドのループが終わる
+        // ランタイムの破棄はブロッキングスレッドの終了を待つ
+        let (closed, _) = mpsc::unbounded_channel();
+        drop(std::mem::replace(&mut self.tx, closed));
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
     }
 }
 
@@ -133,12 +439,15 @@ pub struct FileConverter {
     handle: thread::JoinHandle<Result<()>>,
 }
 
-type Vertex = Vec<f32>;
-type Converter = fn(String, Buffer) -> Result<(PathBuf, Vertex)>;
 impl FileConverter {
     /// コンストラクタ
-    pub fn spawn(paths: Paths, f: Converter) -> Self {
-        let handle = thread::spawn(move || Self::spawn_inner(paths, f));
+    /// f: 読み込んだファイルを変換し，書き出し先と内容を返す
+    pub fn spawn<T, F>(paths: Paths, f: F) -> Self
+    where
+        T: 'static + Send,
+        F: Fn(String, Buffer) -> Result<(PathBuf, Vec<T>)> + Send + Sync + 'static,
+    {
+        let handle = thread::spawn(move || Self::spawn_inner(paths, Arc::new(f)));
         Self { handle }
     }
     /// 実行を完了するまでブロックする
@@ -146,13 +455,18 @@ impl FileConverter {
         self.handle.join().expect("failed to join")
     }
     #[tokio::main(flavor = "current_thread")]
-    async fn spawn_inner(paths: Paths, f: Converter) -> Result<()> {
-        let bufpool = Arc::new(Mutex::new(BufPool::default()));
+    async fn spawn_inner<T, F>(paths: Paths, f: Arc<F>) -> Result<()>
+    where
+        T: 'static + Send,
+        F: Fn(String, Buffer) -> Result<(PathBuf, Vec<T>)> + Send + Sync + 'static,
+    {
+        let bufpool = BufPool::default();
         let h = paths.into_iter().map(|p| {
-            let pool = Arc::clone(&bufpool);
+            let pool = bufpool.clone();
+            let f = Arc::clone(&f);
             task::spawn(async move {
                 let file = &mut fs::File::open(&p).await?;
-                let mut buf = pool.lock().unwrap().get_buffer();
+                let mut buf = pool.get_buffer();
                 file.read_to_end(buf.as_mut()).await?;
                 let (dst, v) = f(p, buf)?;
                 let file = &mut fs::OpenOptions::new()
@@ -161,7 +475,7 @@ impl FileConverter {
                     .write(true)
                     .open(dst)
                     .await?;
-                file.write(unsafe { v.slice_as()? }).await?;
+                file.write_all(unsafe { v.slice_as()? }).await?;
                 Result::<()>::Ok(())
             })
         });
@@ -170,13 +484,436 @@ impl FileConverter {
         }
         Ok(())
     }
+    /// 変換結果を重複排除して1つのパックファイルにまとめる
+    /// 完了するまでブロックする
+    pub fn pack<T, F>(paths: Paths, dst: PathBuf, f: F) -> Result<PackStats>
+    where
+        T: 'static + Send,
+        F: Fn(String, Buffer) -> Result<Vec<T>> + Send + Sync + 'static,
+    {
+        thread::spawn(move || Self::pack_inner(paths, dst, Arc::new(f)))
+            .join()
+            .expect("failed to join")
+    }
+    #[tokio::main(flavor = "current_thread")]
+    async fn pack_inner<T, F>(paths: Paths, dst: PathBuf, f: Arc<F>) -> Result<PackStats>
+    where
+        T: 'static + Send,
+        F: Fn(String, Buffer) -> Result<Vec<T>> + Send + Sync + 'static,
+    {
+        let bufpool = BufPool::default();
+        let mut writer = PackWriter::create(dst, paths.len())?;
+        let h = paths.into_iter().map(|p| {
+            let pool = bufpool.clone();
+            let f = Arc::clone(&f);
+            task::spawn(async move {
+                let file = &mut fs::File::open(&p).await?;
+                let mut buf = pool.get_buffer();
+                file.read_to_end(buf.as_mut()).await?;
+                f(p, buf)
+            })
+        });
+        // フレーム順に追記する
+        for i in h {
+            let v = i.await??;
+            writer.push(unsafe { v.slice_as()? })?;
+        }
+        writer.finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    /// 内容がフレーム番号のファイルを作る
+    fn fixture(name: &str, n: usize) -> Arc<Paths> {
+        let dir = std::env::temp_dir().join(format!("asyncfileio_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = (0..n)
+            .map(|i| {
+                let p = dir.join(format!("{}.txt", i));
+                std::fs::write(&p, i.to_string()).unwrap();
+                p.to_string_lossy().into_owned()
+            })
+            .collect();
+        Arc::new(paths)
+    }
+
+    fn decode(buf: Buffer) -> Result<usize> {
+        Ok(std::str::from_utf8(buf.as_ref())?.parse()?)
+    }
+
+    fn recv<T: Send>(reader: &mut AsyncFileReader<T>) -> (usize, T) {
+        let start = Instant::now();
+        loop {
+            match reader.try_recv() {
+                Ok(res) => return res.unwrap(),
+                Err(TryRecvError::Empty) if start.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reader_order() {
+        let mut reader = AsyncFileReader::spawn(fixture("order", 5), decode);
+        for i in 0..12 {
+            assert_eq!(recv(&mut reader), (i % 5, i % 5));
+        }
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_fetch() {
+        let mut reader = AsyncFileReader::spawn(fixture("fetch", 20), decode);
+        reader.send(Msg::Pause).unwrap();
+        // 一時停止までに読んだものを受け取っておく
+        while reader.recv_timeout(Duration::from_millis(200)).is_ok() {}
+        reader.send(Msg::Fetch(12)).unwrap();
+        let res = reader.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(res.unwrap(), (12, 12));
+        assert_eq!(
+            reader.recv_timeout(Duration::from_millis(100)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+        reader.send(Msg::Terminate).unwrap();
+        assert_eq!(
+            reader.recv_timeout(Duration::from_secs(5)).unwrap_err(),
+            RecvTimeoutError::Disconnected
+        );
+    }
+
+    #[test]
+    fn test_fetch_keeps_playhead() {
+        let config = ReaderConfig {
+            lookahead: 2,
+            ..Default::default()
+        };
+        let mut reader = AsyncFileReader::spawn_with(fixture("fetch_playhead", 20), decode, config);
+        assert_eq!(recv(&mut reader).0, 0);
+        reader.send(Msg::Fetch(15)).unwrap();
+        // 頼んだものは届くが，先読みは続きから
+        let mut got = vec![];
+        while !got.contains(&15) {
+            got.push(recv(&mut reader).0);
+        }
+        got.pop();
+        got.push(recv(&mut reader).0);
+        assert_eq!(got, (1..=got.len()).collect::<Vec<_>>());
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_mapped() {
+        let paths = fixture("mapped", 3);
+        let mut reader =
+            AsyncFileReader::spawn(Source::new(PathList::new(paths).mapped()), |buf| {
+                assert!(buf.is_mapped());
+                decode(buf)
+            });
+        for i in 0..6 {
+            assert_eq!(recv(&mut reader), (i % 3, i % 3));
+        }
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_joined() {
+        fn pair(buf: Buffer) -> Result<(usize, usize)> {
+            let parts = Joined::parts(buf)?;
+            // mmapした領域はコピーされずに届く
+            assert!(parts[0].buf.is_mapped());
+            let part =
+                |i: usize| -> Result<usize> { Ok(std::str::from_utf8(&parts[i].buf)?.parse()?) };
+            Ok((part(0)?, part(1)?))
+        }
+        let streams = vec![
+            Source::new(PathList::new(fixture("joined_a", 5)).mapped()),
+            Source::from(fixture("joined_b", 3)),
+        ];
+        let mut reader = AsyncFileReader::spawn(Source::new(Joined::new(streams)), pair);
+        // 両方が揃ったフレームだけを同じ再生位置で届ける
+        for i in 0..7 {
+            assert_eq!(recv(&mut reader), (i % 3, (i % 3, i % 3)));
+        }
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_seek() {
+        let mut reader = AsyncFileReader::spawn(fixture("seek", 10), decode);
+        assert_eq!(recv(&mut reader).0, 0);
+        reader
+            .send(Msg::Seek {
+                index: 7,
+                flush: true,
+            })
+            .unwrap();
+        assert_eq!(recv(&mut reader).0, 7);
+        assert_eq!(recv(&mut reader).0, 8);
+        // 先読み済みのフレームへのシーク
+        reader
+            .send(Msg::Seek {
+                index: 1,
+                flush: false,
+            })
+            .unwrap();
+        assert_eq!(recv(&mut reader).0, 1);
+        assert_eq!(recv(&mut reader).0, 2);
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_pause_range() {
+        let mut reader = AsyncFileReader::spawn(fixture("pause", 10), decode);
+        reader.send(Msg::Pause).unwrap();
+        reader.send(Msg::SetRange { start: 2, end: 5 }).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 3,
+                flush: true,
+            })
+            .unwrap();
+        // 一時停止中はシーク先だけが届く
+        assert_eq!(recv(&mut reader).0, 3);
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(reader.try_recv(), Err(TryRecvError::Empty)));
+        reader.send(Msg::Resume).unwrap();
+        for i in [4, 2, 3, 4, 2] {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_modes() {
+        let mut reader = AsyncFileReader::spawn(fixture("modes", 4), decode);
+        reader.send(Msg::SetMode(PlayMode::PingPong)).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 2,
+                flush: true,
+            })
+            .unwrap();
+        for i in [2, 3, 2, 1, 0, 1] {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        // 逆方向に複数フレームずつ進める
+        reader.send(Msg::SetMode(PlayMode::Loop)).unwrap();
+        reader.send(Msg::Step(-3)).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 1,
+                flush: true,
+            })
+            .unwrap();
+        for i in [1, 2, 3, 0, 1] {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        // 0.5倍速でも同じフレームは一度しか読まない
+        reader.send(Msg::Speed(0.5)).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 0,
+                flush: true,
+            })
+            .unwrap();
+        for i in [0, 1, 2] {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        reader.send(Msg::Step(1)).unwrap();
+        // 端に着いたら読み込みを止める
+        reader.send(Msg::SetMode(PlayMode::Once)).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 1,
+                flush: true,
+            })
+            .unwrap();
+        for i in [1, 2, 3] {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(reader.try_recv(), Err(TryRecvError::Empty)));
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_error() {
+        let paths = fixture("error", 4);
+        std::fs::remove_file(&paths[1]).unwrap();
+        std::fs::write(&paths[2], "broken").unwrap();
+        let mut reader = AsyncFileReader::spawn(Arc::clone(&paths), decode);
+        let mut next = || {
+            let start = Instant::now();
+            loop {
+                match reader.try_recv() {
+                    Ok(res) => return res,
+                    Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                        thread::sleep(Duration::from_millis(1))
+                    }
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        };
+        assert_eq!(next().unwrap().0, 0);
+        let e = next().unwrap_err();
+        assert_eq!((e.index, e.kind), (1, ErrorKind::NotFound));
+        assert_eq!(e.path, paths[1]);
+        let e = next().unwrap_err();
+        assert_eq!((e.index, e.kind), (2, ErrorKind::Decode));
+        // 2周目以降は失敗したフレームを読まない
+        for i in [3, 0, 3, 0] {
+            assert_eq!(next().unwrap().0, i);
+        }
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_watch() {
+        let paths = fixture("watch", 2);
+        let pattern = paths[0].replace("0.txt", "{}.txt");
+        let source = Source::new(WatchFolder::open(&pattern, 0).unwrap());
+        let mut reader = AsyncFileReader::spawn(source.clone(), decode);
+        reader.send(Msg::SetMode(PlayMode::Once)).unwrap();
+        reader
+            .send(Msg::Seek {
+                index: 0,
+                flush: true,
+            })
+            .unwrap();
+        assert_eq!(recv(&mut reader).0, 0);
+        assert_eq!(recv(&mut reader).0, 1);
+        // 端に着いた後に書き込まれたフレームも届く
+        std::fs::write(pattern.replace("{}", "2"), "2").unwrap();
+        assert_eq!(recv(&mut reader), (2, 2));
+        assert_eq!(source.len(), 3);
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_lookahead() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DECODED: AtomicUsize = AtomicUsize::new(0);
+        fn count(buf: Buffer) -> Result<usize> {
+            DECODED.fetch_add(1, Ordering::SeqCst);
+            decode(buf)
+        }
+        let config = ReaderConfig {
+            lookahead: 3,
+            ..Default::default()
+        };
+        let reader = AsyncFileReader::spawn_with(fixture("lookahead", 100), count, config);
+        // 受信側のチャネルが埋まった後は先読みの分だけ読む
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(
+            DECODED.load(Ordering::SeqCst),
+            AsyncFileReader::<usize>::BUFFER_SIZE + 3
+        );
+        reader.send(Msg::SetLookahead(5)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(
+            DECODED.load(Ordering::SeqCst),
+            AsyncFileReader::<usize>::BUFFER_SIZE + 5
+        );
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_metrics() {
+        let mut reader = AsyncFileReader::spawn(fixture("metrics", 20), decode);
+        for i in 0..3 {
+            assert_eq!(recv(&mut reader).0, i);
+        }
+        thread::sleep(Duration::from_millis(200));
+        let m = reader.metrics();
+        // 受信側が止まっている間は送信先が埋まっている
+        assert_eq!(m.queued, m.queue_capacity);
+        assert_eq!(m.delivered, 3 + m.queued as u64);
+        assert!(m.requested >= m.delivered);
+        assert_eq!(m.read.count, m.decode.count);
+        assert!(m.bytes_read >= m.delivered);
+        reader
+            .send(Msg::Seek {
+                index: 15,
+                flush: true,
+            })
+            .unwrap();
+        assert_eq!(recv(&mut reader).0, 15);
+        let m = reader.metrics();
+        // Seekが届く前に空いた所へ送られたものも捨てる
+        assert!(m.discarded >= m.queue_capacity as u64);
+        assert!(m.cancelled > 0);
+        assert!(m.bytes_per_sec() > 0.0);
+        reader.send(Msg::Terminate).unwrap();
+    }
+
+    #[test]
+    fn test_reader_workers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+        static MAX: AtomicUsize = AtomicUsize::new(0);
+        fn slow(buf: Buffer) -> Result<usize> {
+            let n = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX.fetch_max(n, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            ACTIVE.fetch_sub(1, Ordering::SeqCst);
+            decode(buf)
+        }
+        for workers in [1, 4] {
+            MAX.store(0, Ordering::SeqCst);
+            let config = ReaderConfig {
+                lookahead: 8,
+                decode_workers: workers,
+                io_concurrency: 2,
+                ..Default::default()
+            };
+            let mut reader = AsyncFileReader::spawn_with(fixture("workers", 16), slow, config);
+            // 並列にデコードしても順番通りに届く
+            for i in 0..12 {
+                assert_eq!(recv(&mut reader).0, i);
+            }
+            reader.send(Msg::Terminate).unwrap();
+            assert_eq!(MAX.load(Ordering::SeqCst), workers);
+            // 残っているデコードを待つ
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// プロセスが消費したCPU時間 (clock tick)
+    #[cfg(target_os = "linux")]
+    fn cpu_ticks() -> u64 {
+        let stat = std::fs::read_to_string("/proc/self/stat").unwrap();
+        // コマンド名に空白が含まれても良いように閉じ括弧の後ろから数える
+        let fields: Vec<_> = stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+    }
+
+    /// 受信側が止まっている間はスレッドが眠っている
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_idle_cpu() {
+        let reader = AsyncFileReader::spawn(fixture("idle", 3), decode);
+        thread::sleep(Duration::from_millis(100));
+        let before = cpu_ticks();
+        thread::sleep(Duration::from_millis(500));
+        let used = cpu_ticks() - before;
+        assert!(used < 10, "idle reader used {} ticks", used);
+        reader.send(Msg::Terminate).unwrap();
+    }
 }
@@ -0,0 +1,185 @@
+use crate::{BufPool, PoolStats};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// 2のべき乗マイクロ秒ごとに数える (最後は約35分以上)
+const BUCKETS: usize = 32;
+
+/// 処理時間の分布
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    total_us: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        let i = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+    }
+    fn snapshot(&self) -> Latency {
+        let buckets = std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed));
+        Latency {
+            count: buckets.iter().sum(),
+            total: Duration::from_micros(self.total_us.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// ある時点での処理時間の分布
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    pub count: u64,
+    pub total: Duration,
+    /// i番目は[2^(i-1), 2^i)マイクロ秒
+    buckets: [u64; BUCKETS],
+}
+
+impl Latency {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.total / n as u32,
+        }
+    }
+    /// p (0.0..=1.0) 分位点の上限
+    pub fn percentile(&self, p: f64) -> Duration {
+        let rank = (self.count as f64 * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros((1 << i) - 1);
+            }
+        }
+        Duration::ZERO
+    }
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+}
+
+/// 読み込みスレッドの統計
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// 読み込みにかかった時間
+    pub read: Latency,
+    /// デコードにかかった時間
+    pub decode: Latency,
+    pub bytes_read: u64,
+    /// 読み込みスレッドを立ち上げてからの時間
+    pub elapsed: Duration,
+    /// 読み始めたフレーム数
+    pub requested: u64,
+    /// 届けたフレーム数 (失敗を含まない)
+    pub delivered: u64,
+    pub failed: u64,
+    /// 再生位置から外れて取り消した読み込み
+    pub cancelled: u64,
+    /// Seekより前に読まれたので受信側で捨てたフレーム
+    pub discarded: u64,
+    /// 読み込み中またはデコード中のフレーム数
+    pub in_flight: usize,
+    /// 届けたがまだ受信されていないフレーム数
+    pub queued: usize,
+    pub queue_capacity: usize,
+    /// 読み込み用のバッファのプール
+    pub pool: PoolStats,
+}
+
+impl Metrics {
+    pub fn bytes_per_sec(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            t if t > 0.0 => self.bytes_read as f64 / t,
+            _ => 0.0,
+        }
+    }
+}
+
+/// 読み込みスレッドと受信側で共有して数える
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    read: Histogram,
+    decode: Histogram,
+    bytes_read: AtomicU64,
+    pub requested: AtomicU64,
+    pub delivered: AtomicU64,
+    pub failed: AtomicU64,
+    pub cancelled: AtomicU64,
+    pub discarded: AtomicU64,
+    pub in_flight: AtomicUsize,
+    pub queued: AtomicUsize,
+    queue_capacity: usize,
+    pool: BufPool,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn new(queue_capacity: usize, pool: BufPool) -> Self {
+        Self {
+            read: Histogram::default(),
+            decode: Histogram::default(),
+            bytes_read: AtomicU64::new(0),
+            requested: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            queue_capacity,
+            pool,
+            start: Instant::now(),
+        }
+    }
+    pub fn read(&self, d: Duration, bytes: usize) {
+        self.read.record(d);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn decode(&self, d: Duration) {
+        self.decode.record(d);
+    }
+    pub fn snapshot(&self) -> Metrics {
+        let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        Metrics {
+            read: self.read.snapshot(),
+            decode: self.decode.snapshot(),
+            bytes_read: load(&self.bytes_read),
+            elapsed: self.start.elapsed(),
+            requested: load(&self.requested),
+            delivered: load(&self.delivered),
+            failed: load(&self.failed),
+            cancelled: load(&self.cancelled),
+            discarded: load(&self.discarded),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            queue_capacity: self.queue_capacity,
+            pool: self.pool.stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency() {
+        let h = Histogram::default();
+        assert_eq!(h.snapshot().mean(), Duration::ZERO);
+        for ms in [1, 2, 3, 100] {
+            h.record(Duration::from_millis(ms));
+        }
+        let l = h.snapshot();
+        assert_eq!(l.count, 4);
+        assert_eq!(l.mean(), Duration::from_micros(26500));
+        // 分位点はバケットの上限で近似する
+        assert_eq!(l.percentile(0.5), Duration::from_micros(2047));
+        assert_eq!(l.percentile(0.75), Duration::from_micros(4095));
+        assert_eq!(l.percentile(1.0), Duration::from_micros(131071));
+        assert!(l.percentile(1.0) >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fmt, fs::File, io, ops::Deref, path::Path};
+
+/// 読み込み専用のメモリマップ
+/// マップ中にファイルを切り詰めるとアクセス時にSIGBUSになるので，書き換えないファイルにのみ使う
+/// unix以外ではファイル全体を読み込んだもので代用する
+pub struct Mmap {
+    #[cfg(unix)]
+    ptr: *mut libc::c_void,
+    #[cfg(unix)]
+    len: usize,
+    #[cfg(not(unix))]
+    data: Vec<u8>,
+}
+
+// 読み込み専用なのでスレッド間で共有してよい
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    #[cfg(unix)]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // 長さ0はmmapできない
+        if len == 0 {
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // カーネルに先読みさせる (失敗しても読めるので無視する)
+        unsafe { libc::madvise(ptr, len, libc::MADV_WILLNEED) };
+        Ok(Self { ptr, len })
+    }
+    #[cfg(not(unix))]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        use std::io::Read;
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(Self { data })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+impl fmt::Debug for Mmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mmap").field("len", &self.len()).finish()
+    }
+}
@@ -0,0 +1,290 @@
+use crate::{Buffer, FrameSource, Mmap};
+use anyhow::{ensure, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"OAPK";
+const VERSION: u32 = 1;
+/// 頂点配列をそのままスライスとして扱えるようにペイロードを揃える
+const ALIGN: u64 = 16;
+
+/// 連番フレームを1ファイルにまとめたもの
+/// 同一内容のフレームは1つのペイロードを共有する
+///
+/// レイアウト (リトルエンディアン)
+/// magic, version, frame数, ペイロード数(u32)
+/// フレーム -> ペイロード番号 (u32 * frame数)
+/// ペイロードの(offset, len) (u64 * 2 * frame数, 使わない分は0)
+/// ペイロード本体
+#[derive(Debug)]
+pub struct Pack {
+    path: PathBuf,
+    frames: Vec<u32>,
+    payloads: Vec<(u64, u64)>,
+    /// open_mappedで開いた場合はファイル全体のマップ
+    map: Option<Arc<Mmap>>,
+}
+
+impl Pack {
+    /// ヘッダのみを読み込む
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = File::open(&path)?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header)?;
+        let get = |b: &[u8], i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        ensure!(&header[..4] == MAGIC, "not a pack file: {:?}", path);
+        ensure!(get(&header, 4) == VERSION, "unsupported pack version");
+        let frame_num = get(&header, 8) as usize;
+        let payload_num = get(&header, 12) as usize;
+        let mut table = vec![0; frame_num * 4 + frame_num * 16];
+        file.read_exact(&mut table)?;
+        let frames: Vec<u32> = (0..frame_num).map(|i| get(&table, i * 4)).collect();
+        let table = &table[frame_num * 4..];
+        let get64 = |i: usize| u64::from_le_bytes(table[i..i + 8].try_into().unwrap());
+        let payloads = (0..payload_num)
+            .map(|i| (get64(i * 16), get64(i * 16 + 8)))
+            .collect();
+        ensure!(
+            frames.iter().all(|&p| (p as usize) < payload_num),
+            "broken pack index: {:?}",
+            path
+        );
+        Ok(Self {
+            path,
+            frames,
+            payloads,
+            map: None,
+        })
+    }
+    /// ファイル全体をmmapして開く
+    /// ペイロードはコピーせずにマップの一部として読む
+    pub fn open_mapped(path: impl AsRef<Path>) -> Result<Self> {
+        let mut pack = Self::open(&path)?;
+        let map = Mmap::open(&path)?;
+        ensure!(
+            pack.payloads
+                .iter()
+                .all(|&(offset, len)| offset + len <= map.len() as u64),
+            "truncated pack payload: {:?}",
+            pack.path
+        );
+        pack.map = Some(Arc::new(map));
+        Ok(pack)
+    }
+    /// フレーム数
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+    /// 重複を除いたペイロード数
+    pub fn payload_count(&self) -> usize {
+        self.payloads.len()
+    }
+    /// フレームが参照するペイロード番号
+    pub fn payload_of(&self, frame: usize) -> usize {
+        self.frames[frame] as usize
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// mmapしていればフレームの内容の位置を返す
+    pub(crate) fn mapped(&self, frame: usize) -> Option<(Arc<Mmap>, Range<usize>)> {
+        let (offset, len) = self.payloads[self.payload_of(frame)];
+        let (offset, len) = (offset as usize, len as usize);
+        self.map
+            .as_ref()
+            .map(|m| (Arc::clone(m), offset..offset + len))
+    }
+    /// フレームの内容のバイト数
+    pub fn payload_len(&self, frame: usize) -> usize {
+        self.payloads[self.payload_of(frame)].1 as usize
+    }
+    /// フレームの内容をbufの末尾に読み込む
+    pub(crate) fn read_into(&self, frame: usize, buf: &mut Vec<u8>) -> Result<()> {
+        let (offset, len) = self.payloads[self.payload_of(frame)];
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.take(len).read_to_end(buf)?;
+        ensure!(
+            read as u64 == len,
+            "truncated pack payload: {:?}",
+            self.path
+        );
+        Ok(())
+    }
+}
+
+impl FrameSource for Pack {
+    fn len(&self) -> usize {
+        Pack::len(self)
+    }
+    fn name(&self, index: usize) -> String {
+        format!("{}#{}", self.path().display(), index)
+    }
+    fn payload_of(&self, index: usize) -> usize {
+        Pack::payload_of(self, index)
+    }
+    fn size_hint(&self, index: usize) -> Option<usize> {
+        // mmapする場合はバッファを使わない
+        self.map.is_none().then(|| self.payload_len(index))
+    }
+    fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+        match self.mapped(index) {
+            Some((map, range)) => Ok(buf.into_mapped(map, range)),
+            None => {
+                self.read_into(index, buf.as_mut())?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// 書き出し結果
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackStats {
+    pub frames: usize,
+    pub payloads: usize,
+    /// 重複排除で書かずに済んだバイト数
+    pub saved_bytes: u64,
+}
+
+/// フレームを順番に追記してパックを作る
+/// ハッシュが一致したものは書き出し済みの内容と比較してから共有する
+pub struct PackWriter {
+    file: File,
+    frame_num: usize,
+    frames: Vec<u32>,
+    payloads: Vec<(u64, u64)>,
+    hashes: HashMap<u64, Vec<u32>>,
+    end: u64,
+    stats: PackStats,
+}
+
+impl PackWriter {
+    /// frame_num: 書き込むフレーム数 (ヘッダの領域を確保する)
+    pub fn create(path: impl AsRef<Path>, frame_num: usize) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let header = 16 + frame_num as u64 * 20;
+        Ok(Self {
+            file,
+            frame_num,
+            frames: Vec::with_capacity(frame_num),
+            payloads: Vec::new(),
+            hashes: HashMap::new(),
+            end: align(header),
+            stats: PackStats::default(),
+        })
+    }
+    /// 次のフレームを追加し，参照するペイロード番号を返す
+    pub fn push(&mut self, data: &[u8]) -> Result<usize> {
+        ensure!(self.frames.len() < self.frame_num, "too many frames");
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let hash = hasher.finish();
+        let candidates = self.hashes.get(&hash).cloned().unwrap_or_default();
+        for id in candidates {
+            if self.equals(id as usize, data)? {
+                self.frames.push(id);
+                self.stats.saved_bytes += data.len() as u64;
+                return Ok(id as usize);
+            }
+        }
+        let id = self.payloads.len() as u32;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(data)?;
+        self.payloads.push((self.end, data.len() as u64));
+        self.end = align(self.end + data.len() as u64);
+        self.hashes.entry(hash).or_default().push(id);
+        self.frames.push(id);
+        Ok(id as usize)
+    }
+    fn equals(&mut self, id: usize, data: &[u8]) -> Result<bool> {
+        let (offset, len) = self.payloads[id];
+        if len != data.len() as u64 {
+            return Ok(false);
+        }
+        let mut written = vec![0; data.len()];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut written)?;
+        Ok(written == data)
+    }
+    /// ヘッダを書き込んで閉じる
+    pub fn finish(mut self) -> Result<PackStats> {
+        ensure!(
+            self.frames.len() == self.frame_num,
+            "expected {} frames, but got {}",
+            self.frame_num,
+            self.frames.len()
+        );
+        let mut header = Vec::with_capacity(16 + self.frame_num * 20);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.frame_num as u32).to_le_bytes());
+        header.extend_from_slice(&(self.payloads.len() as u32).to_le_bytes());
+        self.frames
+            .iter()
+            .for_each(|f| header.extend_from_slice(&f.to_le_bytes()));
+        for i in 0..self.frame_num {
+            let (offset, len) = self.payloads.get(i).copied().unwrap_or_default();
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&len.to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.flush()?;
+        self.stats.frames = self.frame_num;
+        self.stats.payloads = self.payloads.len();
+        Ok(self.stats)
+    }
+}
+
+fn align(x: u64) -> u64 {
+    x.div_ceil(ALIGN) * ALIGN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_dedup() {
+        let path = std::env::temp_dir().join(format!("asyncfileio_pack_{}", std::process::id()));
+        let frames: [&[u8]; 5] = [b"aaaa", b"bbbbbbb", b"aaaa", b"aaaa", b"c"];
+        let mut w = PackWriter::create(&path, frames.len()).unwrap();
+        for f in frames {
+            w.push(f).unwrap();
+        }
+        let stats = w.finish().unwrap();
+        assert_eq!(stats.payloads, 3);
+        assert_eq!(stats.saved_bytes, 8);
+
+        let pack = Pack::open(&path).unwrap();
+        assert_eq!(pack.len(), 5);
+        assert_eq!(pack.payload_count(), 3);
+        assert_eq!(pack.payload_of(0), pack.payload_of(3));
+        assert_ne!(pack.payload_of(0), pack.payload_of(1));
+        let mapped = Pack::open_mapped(&path).unwrap();
+        for (i, f) in frames.iter().enumerate() {
+            let mut buf = vec![];
+            pack.read_into(i, &mut buf).unwrap();
+            assert_eq!(&buf, f);
+            assert_eq!(pack.size_hint(i), Some(f.len()));
+            let (map, range) = mapped.mapped(i).unwrap();
+            assert_eq!(&&map[range], f);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
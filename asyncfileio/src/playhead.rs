@@ -0,0 +1,325 @@
+/// 端に来た時の振る舞い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlayMode {
+    /// 端で止まる
+    Once,
+    /// 反対の端に戻る
+    #[default]
+    Loop,
+    /// 端で折り返す
+    PingPong,
+    /// start..endの範囲だけを繰り返す
+    LoopRange { start: usize, end: usize },
+}
+
+/// 再生位置
+/// 読み込み側と表示側が同じ規則で次のフレームを決めるために使う
+#[derive(Debug, Clone)]
+pub struct Playhead {
+    len: usize,
+    mode: PlayMode,
+    /// 1回のadvanceで進むフレーム数 (負なら逆再生)
+    /// 1未満なら同じフレームを複数回返す
+    speed: f64,
+    /// 範囲の先頭からの位置
+    /// PingPongでは往復を1周期として数える
+    phase: f64,
+}
+
+impl Playhead {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            mode: PlayMode::default(),
+            speed: 1.0,
+            phase: 0.0,
+        }
+    }
+    /// フレーム数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn mode(&self) -> PlayMode {
+        self.mode
+    }
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+    /// 現在位置を保ったまま切り替える
+    /// 範囲外にいる場合は範囲の先頭に移る
+    pub fn set_mode(&mut self, mode: PlayMode) {
+        let index = self.index();
+        self.mode = mode;
+        self.seek(index);
+    }
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+    /// フレーム数を変える (範囲内なら現在位置を保つ)
+    pub fn set_len(&mut self, len: usize) {
+        let index = self.index();
+        self.len = len;
+        self.seek(index);
+    }
+    /// 再生範囲
+    /// 空の範囲は全体として扱う
+    pub fn bounds(&self) -> (usize, usize) {
+        match self.mode {
+            PlayMode::LoopRange { start, end } if start < end.min(self.len) => {
+                (start, end.min(self.len))
+            }
+            _ => (0, self.len),
+        }
+    }
+    /// 1周期の長さ
+    fn period(&self) -> usize {
+        let (start, end) = self.bounds();
+        match self.mode {
+            PlayMode::PingPong => (2 * (end - start)).saturating_sub(2).max(1),
+            _ => end - start,
+        }
+    }
+    pub fn index(&self) -> usize {
+        let (start, end) = self.bounds();
+        let phase = self.phase as usize;
+        if phase < end - start {
+            start + phase
+        } else {
+            // PingPongの復路
+            start + self.period() - phase
+        }
+    }
+    /// 範囲外の場合は範囲の先頭に移る
+    pub fn seek(&mut self, index: usize) {
+        let (start, end) = self.bounds();
+        self.phase = if (start..end).contains(&index) {
+            (index - start) as f64
+        } else {
+            0.0
+        };
+    }
+    /// 次のフレームに進める
+    /// Onceで端に着いた場合はNoneを返し，位置は変えない
+    pub fn advance(&mut self) -> Option<usize> {
+        let period = self.period() as f64;
+        if self.len == 0 {
+            return None;
+        }
+        let next = self.phase + self.speed;
+        if self.mode == PlayMode::Once && !(0.0..period).contains(&next) {
+            return None;
+        }
+        self.phase = next.rem_euclid(period);
+        // 丸め誤差で周期ちょうどになることがある
+        if self.phase >= period {
+            self.phase = 0.0;
+        }
+        Some(self.index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(mode: PlayMode, speed: f64, from: usize, n: usize) -> Vec<usize> {
+        let mut p = Playhead::new(5);
+        p.set_mode(mode);
+        p.set_speed(speed);
+        p.seek(from);
+        (0..n).map_while(|_| p.advance()).collect()
+    }
+
+    #[test]
+    fn test_loop() {
+        assert_eq!(play(PlayMode::Loop, 1.0, 3, 4), [4, 0, 1, 2]);
+        assert_eq!(play(PlayMode::Loop, -1.0, 1, 3), [0, 4, 3]);
+        assert_eq!(play(PlayMode::Loop, 2.0, 0, 3), [2, 4, 1]);
+    }
+
+    #[test]
+    fn test_once() {
+        assert_eq!(play(PlayMode::Once, 1.0, 2, 10), [3, 4]);
+        assert_eq!(play(PlayMode::Once, -1.0, 2, 10), [1, 0]);
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        assert_eq!(
+            play(PlayMode::PingPong, 1.0, 2, 8),
+            [3, 4, 3, 2, 1, 0, 1, 2]
+        );
+        assert_eq!(play(PlayMode::PingPong, -1.0, 1, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_speed() {
+        assert_eq!(play(PlayMode::Loop, 0.5, 3, 5), [3, 4, 4, 0, 0]);
+        assert_eq!(play(PlayMode::Loop, -3.0, 1, 3), [3, 0, 2]);
+        assert_eq!(play(PlayMode::Once, 0.5, 3, 10), [3, 4, 4]);
+        assert_eq!(play(PlayMode::PingPong, 2.0, 1, 4), [3, 3, 1, 1]);
+    }
+
+    /// テスト用の乱数 (xorshift)
+    struct Rng(u64);
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+        fn mode(&mut self, len: usize) -> PlayMode {
+            match self.below(4) {
+                0 => PlayMode::Once,
+                1 => PlayMode::Loop,
+                2 => PlayMode::PingPong,
+                _ => PlayMode::LoopRange {
+                    start: self.below(len as u64 + 1) as usize,
+                    end: self.below(len as u64 + 2) as usize,
+                },
+            }
+        }
+    }
+
+    /// 1フレームずつ動かす素朴な実装
+    fn reference(mode: PlayMode, len: usize, from: usize, step: isize, n: usize) -> Vec<usize> {
+        let (start, end) = match mode {
+            PlayMode::LoopRange { start, end } if start < end.min(len) => (start, end.min(len)),
+            _ => (0, len),
+        };
+        let mut i = if (start..end).contains(&from) {
+            from
+        } else {
+            start
+        };
+        let mut dir = 1;
+        let mut out = vec![];
+        for _ in 0..n {
+            if mode == PlayMode::Once {
+                let next = i as isize + step;
+                if next < start as isize || next >= end as isize {
+                    break;
+                }
+                i = next as usize;
+                out.push(i);
+                continue;
+            }
+            for _ in 0..step.unsigned_abs() {
+                let d = step.signum() * dir;
+                let next = i as isize + d;
+                if (start as isize..end as isize).contains(&next) {
+                    i = next as usize;
+                } else if mode == PlayMode::PingPong {
+                    dir = -dir;
+                    if end - start > 1 {
+                        i = (i as isize - d) as usize;
+                    }
+                } else if d > 0 {
+                    i = start;
+                } else {
+                    i = end - 1;
+                }
+            }
+            out.push(i);
+        }
+        out
+    }
+
+    #[test]
+    fn test_property_step() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..2000 {
+            let len = 1 + rng.below(12) as usize;
+            let mode = rng.mode(len);
+            let from = rng.below(len as u64) as usize;
+            let step = rng.below(15) as isize - 7;
+            let mut p = Playhead::new(len);
+            p.set_mode(mode);
+            p.set_speed(step as f64);
+            p.seek(from);
+            let got: Vec<_> = (0..40).map_while(|_| p.advance()).collect();
+            let (start, end) = p.bounds();
+            assert!(got.iter().all(|i| (start..end).contains(i)));
+            assert_eq!(
+                got,
+                reference(mode, len, from, step, 40),
+                "{:?} len={} from={} step={}",
+                mode,
+                len,
+                from,
+                step
+            );
+        }
+    }
+
+    /// 1/d倍速でd回進めると等倍で1回進めたのと同じになる
+    #[test]
+    fn test_property_speed() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..2000 {
+            let len = 1 + rng.below(12) as usize;
+            let mode = rng.mode(len);
+            let from = rng.below(len as u64) as usize;
+            let step = rng.below(9) as isize - 4;
+            let d = 1 << rng.below(4);
+            let (mut a, mut b) = (Playhead::new(len), Playhead::new(len));
+            a.set_mode(mode);
+            b.set_mode(mode);
+            a.set_speed(step as f64);
+            b.set_speed(step as f64 / d as f64);
+            a.seek(from);
+            b.seek(from);
+            for _ in 0..30 {
+                let mut x = None;
+                for _ in 0..d {
+                    let prev = b.index();
+                    x = b.advance();
+                    // 1未満の速度では1フレームより大きく飛ばない
+                    if let (Some(x), true) = (x, step.abs() < d) {
+                        let diff = x.abs_diff(prev);
+                        let (start, end) = b.bounds();
+                        assert!(diff <= 1 || diff == end - start - 1);
+                    }
+                }
+                let y = a.advance();
+                if mode == PlayMode::Once && y.is_none() {
+                    break;
+                }
+                assert_eq!(
+                    x, y,
+                    "{:?} len={} from={} step={}/{}",
+                    mode, len, from, step, d
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        let range = PlayMode::LoopRange { start: 1, end: 3 };
+        assert_eq!(play(range, 1.0, 1, 4), [2, 1, 2, 1]);
+        // 範囲外からは範囲の先頭に移る
+        assert_eq!(play(range, 1.0, 4, 2), [2, 1]);
+        // 空の範囲は全体
+        let empty = PlayMode::LoopRange { start: 3, end: 3 };
+        assert_eq!(play(empty, 1.0, 3, 3), [4, 0, 1]);
+    }
+
+    #[test]
+    fn test_set_len() {
+        let mut p = Playhead::new(3);
+        p.set_mode(PlayMode::Once);
+        p.seek(2);
+        assert_eq!(p.advance(), None);
+        // 伸びた分だけ先に進める
+        p.set_len(5);
+        assert_eq!(p.index(), 2);
+        assert_eq!(p.advance(), Some(3));
+        p.set_len(2);
+        assert_eq!(p.index(), 0);
+    }
+}
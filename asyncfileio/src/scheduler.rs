@@ -0,0 +1,240 @@
+use crate::{PlayMode, Playhead};
+use std::collections::HashSet;
+
+/// 再生位置から近い順に先読みするフレームを決める
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduler {
+    /// 次に届けるフレーム
+    cursor: Playhead,
+    /// Onceで端まで届け終えた
+    ended: bool,
+    pub paused: bool,
+    /// 一時停止中でもcursorのフレームだけは読む
+    wanted: bool,
+    /// 直前に届けたペイロード (静止したフレームは読み直さない)
+    delivered: Option<usize>,
+    /// 読み込みに失敗したペイロードは先読みしない
+    failed: HashSet<usize>,
+    pub lookahead: usize,
+}
+
+impl Scheduler {
+    pub fn new(len: usize, lookahead: usize) -> Self {
+        Self {
+            cursor: Playhead::new(len),
+            ended: len == 0,
+            paused: false,
+            wanted: false,
+            delivered: None,
+            failed: HashSet::new(),
+            lookahead: lookahead.max(1),
+        }
+    }
+    /// 再生方向に沿って近い順に，まだ届けていないフレームとその位置を返す
+    /// 同じペイロードのフレームは最初の1つだけを含む
+    pub fn window(&self, payload_of: impl Fn(usize) -> usize) -> Vec<(usize, Playhead)> {
+        let mut out: Vec<(usize, Playhead)> = vec![];
+        if self.ended {
+            return out;
+        }
+        let mut p = self.cursor.clone();
+        let mut prev = self.delivered;
+        let mut seen = HashSet::new();
+        // 低速再生では同じフレームが続くので，その分だけ多めに辿る
+        // 読まないもの (静止したフレームや失敗したもの) が長く続いても先読みの数倍で打ち切る
+        // 速度0では進まないので今のフレームだけ
+        let speed = p.speed().abs();
+        let steps = match speed > 0.0 {
+            true => (2 * p.len()).min(4 * self.lookahead) as f64 / speed.clamp(1.0 / 64.0, 1.0),
+            false => 1.0,
+        };
+        for _ in 0..steps.ceil() as usize {
+            if out.len() >= self.lookahead {
+                break;
+            }
+            let index = p.index();
+            let payload = payload_of(index);
+            if prev != Some(payload) && !self.failed.contains(&payload) && seen.insert(payload) {
+                out.push((index, p.clone()));
+            }
+            prev = Some(payload);
+            if p.advance().is_none() {
+                break;
+            }
+        }
+        out
+    }
+    /// windowのうち新しく読み始めてよい数
+    pub fn reads(&self) -> usize {
+        match self.paused {
+            true => self.wanted as usize,
+            false => self.lookahead,
+        }
+    }
+    /// windowで返した位置のフレームを届けた
+    pub fn deliver(&mut self, at: Playhead, payload: usize, ok: bool) {
+        self.cursor = at;
+        self.ended = self.cursor.advance().is_none();
+        self.delivered = Some(payload);
+        self.wanted = false;
+        if !ok {
+            self.failed.insert(payload);
+        }
+    }
+    /// 次にindexを届ける
+    /// 失敗したフレームでも読み直す
+    pub fn seek(&mut self, index: usize, payload_of: impl Fn(usize) -> usize) {
+        self.cursor.seek(index);
+        self.ended = false;
+        self.delivered = None;
+        self.wanted = true;
+        self.failed.remove(&payload_of(self.cursor.index()));
+    }
+    /// 失敗したフレームもすべて読み直す
+    pub fn reload(&mut self, index: usize) {
+        self.cursor.seek(index);
+        self.ended = false;
+        self.delivered = None;
+        self.failed.clear();
+    }
+    pub fn set_speed(&mut self, speed: f64) {
+        self.cursor.set_speed(speed);
+        self.ended = false;
+    }
+    /// フレーム数が変わった
+    /// Onceで端に着いていた場合は伸びた分を続けて届ける
+    pub fn set_len(&mut self, len: usize) {
+        let was_empty = self.cursor.is_empty();
+        self.cursor.set_len(len);
+        self.ended = match self.ended && !was_empty {
+            true => self.cursor.advance().is_none(),
+            false => len == 0,
+        };
+    }
+    /// フレームの内容が変わったので，次に来た時に読み直す
+    pub fn modified(&mut self, payload: usize) {
+        self.failed.remove(&payload);
+        if self.delivered == Some(payload) {
+            self.delivered = None;
+        }
+    }
+    pub fn set_mode(&mut self, mode: PlayMode) {
+        self.cursor.set_mode(mode);
+        self.ended = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indices(s: &Scheduler, payload_of: impl Fn(usize) -> usize) -> Vec<usize> {
+        s.window(payload_of).into_iter().map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn test_window() {
+        let id = |i| i;
+        let mut s = Scheduler::new(10, 3);
+        assert_eq!(indices(&s, id), [0, 1, 2]);
+        s.seek(8, id);
+        assert_eq!(indices(&s, id), [8, 9, 0]);
+        s.set_speed(-1.0);
+        assert_eq!(indices(&s, id), [8, 7, 6]);
+        // 届けたら次のフレームから
+        let (i, at) = s.window(id).remove(0);
+        s.deliver(at, i, true);
+        assert_eq!(indices(&s, id), [7, 6, 5]);
+        // 端で折り返す先を読む
+        s.set_mode(PlayMode::PingPong);
+        s.set_speed(1.0);
+        s.lookahead = 4;
+        assert_eq!(indices(&s, id), [7, 8, 9, 6]);
+    }
+
+    #[test]
+    fn test_window_payload() {
+        // 2フレームずつ同じ内容
+        let pair = |i: usize| i / 2;
+        let mut s = Scheduler::new(8, 3);
+        assert_eq!(indices(&s, pair), [0, 2, 4]);
+        let (i, at) = s.window(pair).remove(0);
+        s.deliver(at, pair(i), false);
+        assert_eq!(indices(&s, pair), [2, 4, 6]);
+        // 失敗したペイロードは一周しても読まない
+        s.seek(6, pair);
+        assert_eq!(indices(&s, pair), [6, 2, 4]);
+        // 明示的なシークでは読み直す
+        s.seek(1, pair);
+        assert_eq!(indices(&s, pair), [1, 2, 4]);
+        // 0.5倍速でも同じフレームは1度だけ
+        s.set_speed(0.5);
+        s.seek(0, pair);
+        assert_eq!(indices(&s, pair), [0, 2, 4]);
+    }
+
+    #[test]
+    fn test_stopped() {
+        let id = |i| i;
+        let mut s = Scheduler::new(1000, 3);
+        s.set_speed(0.0);
+        s.seek(5, id);
+        assert_eq!(indices(&s, id), [5]);
+        let (i, at) = s.window(id).remove(0);
+        s.deliver(at, i, true);
+        assert!(s.window(id).is_empty());
+    }
+
+    #[test]
+    fn test_long_hold() {
+        // 最後の数フレーム以外は同じ内容
+        let calls = std::cell::Cell::new(0);
+        let hold = |i: usize| {
+            calls.set(calls.get() + 1);
+            i.saturating_sub(999_990)
+        };
+        let mut s = Scheduler::new(1_000_000, 3);
+        let (i, at) = s.window(hold).remove(0);
+        s.deliver(at, hold(i), true);
+        // 長さではなく先読みの数に比例してしか辿らない
+        calls.set(0);
+        assert!(s.window(hold).is_empty());
+        assert!(calls.get() <= 4 * 3);
+    }
+
+    #[test]
+    fn test_paused() {
+        let id = |i| i;
+        let mut s = Scheduler::new(5, 3);
+        s.paused = true;
+        assert_eq!(s.reads(), 0);
+        s.seek(2, id);
+        assert_eq!(s.reads(), 1);
+        let (i, at) = s.window(id).remove(0);
+        s.deliver(at, i, true);
+        assert_eq!(s.reads(), 0);
+        s.paused = false;
+        assert_eq!(s.reads(), 3);
+    }
+
+    #[test]
+    fn test_once() {
+        let id = |i| i;
+        let mut s = Scheduler::new(4, 8);
+        s.set_mode(PlayMode::Once);
+        s.seek(2, id);
+        assert_eq!(indices(&s, id), [2, 3]);
+        for _ in 0..2 {
+            let (i, at) = s.window(id).remove(0);
+            s.deliver(at, i, true);
+        }
+        assert!(s.window(id).is_empty());
+        s.set_len(6);
+        assert_eq!(indices(&s, id), [4, 5]);
+        // 空の状態から伸びたら先頭から
+        let mut s = Scheduler::new(0, 8);
+        assert!(s.window(id).is_empty());
+        s.set_len(2);
+        assert_eq!(indices(&s, id), [0, 1]);
+    }
+}
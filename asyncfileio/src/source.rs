@@ -0,0 +1,108 @@
+use crate::{Buffer, Mmap, Paths};
+use anyhow::Result;
+use std::{fmt, fs::File, io::Read, ops::Deref, sync::Arc};
+
+/// フレームの読み込み元
+/// readはブロッキングスレッドから呼ばれる
+pub trait FrameSource: fmt::Debug + Send + Sync {
+    /// フレーム数
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// 表示用のフレーム名
+    fn name(&self, index: usize) -> String;
+    /// 同じ内容のフレームには同じ番号を返す
+    fn payload_of(&self, index: usize) -> usize {
+        index
+    }
+    /// 読み込む前に分かるフレームのバイト数 (バッファの確保に使う)
+    fn size_hint(&self, _index: usize) -> Option<usize> {
+        None
+    }
+    /// 変化を通知する関数を登録する
+    /// 内容が変わらない読み込み元では何もしない
+    fn watch(&self, _notify: Notify) {}
+    /// フレームの内容を読み込む
+    /// bufはプールから借りた空のバッファで，mmapする場合は使わずにinto_mappedで置き換える
+    fn read(&self, index: usize, buf: Buffer) -> Result<Buffer>;
+}
+
+/// 読み込み元の変化
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// フレームが増えて全体がlenになった
+    Added { len: usize },
+    /// フレームの内容が書き換わった
+    Modified(usize),
+}
+
+/// 変化を受け取る関数 (読み込み元のスレッドから呼ばれる)
+pub type Notify = Box<dyn Fn(Change) + Send>;
+
+/// 共有できるフレームの読み込み元
+#[derive(Debug, Clone)]
+pub struct Source(Arc<dyn FrameSource>);
+
+impl Source {
+    pub fn new(source: impl FrameSource + 'static) -> Self {
+        Self(Arc::new(source))
+    }
+}
+
+impl Deref for Source {
+    type Target = dyn FrameSource;
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl<S: FrameSource + 'static> From<Arc<S>> for Source {
+    fn from(source: Arc<S>) -> Self {
+        Self(source)
+    }
+}
+
+impl From<Arc<Paths>> for Source {
+    fn from(paths: Arc<Paths>) -> Self {
+        Self::new(PathList::new(paths))
+    }
+}
+
+/// 1フレーム1ファイルの連番
+#[derive(Debug, Clone)]
+pub struct PathList {
+    paths: Arc<Paths>,
+    mmap: bool,
+}
+
+impl PathList {
+    pub fn new(paths: Arc<Paths>) -> Self {
+        Self { paths, mmap: false }
+    }
+    /// コピーせずにmmapして読む
+    pub fn mapped(self) -> Self {
+        Self { mmap: true, ..self }
+    }
+}
+
+impl FrameSource for PathList {
+    fn len(&self) -> usize {
+        self.paths.len()
+    }
+    fn name(&self, index: usize) -> String {
+        self.paths[index].clone()
+    }
+    fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+        if self.mmap {
+            let map = Mmap::open(&self.paths[index])?;
+            let len = map.len();
+            return Ok(buf.into_mapped(Arc::new(map), 0..len));
+        }
+        let mut file = File::open(&self.paths[index])?;
+        let len = file.metadata()?.len() as usize;
+        buf.as_mut().reserve(len);
+        file.read_to_end(buf.as_mut())?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{Buffer, Change, FrameSource, Notify};
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// 書き込まれていく連番を監視する
+/// 先頭から途切れずに並んだファイルをフレームとし，続きのファイルが現れたら伸ばす
+/// linuxではinotify，それ以外では定期的なポーリングで変化を調べる
+pub struct WatchFolder {
+    shared: Arc<Shared>,
+    watcher: Option<Watcher>,
+}
+
+struct Shared {
+    /// "{}"の前後
+    prefix: String,
+    suffix: String,
+    /// 0番目のフレームのファイル番号
+    start: usize,
+    len: AtomicUsize,
+    subscribers: Mutex<Vec<Notify>>,
+}
+
+impl Shared {
+    /// 監視するディレクトリ (区切り文字を含む)
+    fn dir(&self) -> &str {
+        let end = self.prefix.rfind(std::path::is_separator);
+        &self.prefix[..end.map_or(0, |i| i + 1)]
+    }
+    fn path(&self, index: usize) -> String {
+        format!("{}{}{}", self.prefix, self.start + index, self.suffix)
+    }
+    /// ファイル名からフレーム番号を求める
+    fn index_of(&self, name: &str) -> Option<usize> {
+        let file = &self.prefix[self.dir().len()..];
+        let number = name
+            .strip_prefix(file)?
+            .strip_suffix(self.suffix.as_str())?;
+        let n: usize = number.parse().ok()?;
+        // 0埋めなど，pathで作れない名前は別のファイル
+        (n.to_string() == number && n >= self.start).then(|| n - self.start)
+    }
+    /// 監視スレッドからのみ呼ぶ
+    fn changed(&self, index: usize) {
+        let len = self.len.load(Ordering::SeqCst);
+        if index < len {
+            return self.notify(Change::Modified(index));
+        }
+        // 間が空いている間は伸ばさない
+        if index > len {
+            return;
+        }
+        let new = (len..)
+            .find(|&i| !Path::new(&self.path(i)).exists())
+            .unwrap();
+        if new == len {
+            return;
+        }
+        let len = new;
+        self.len.store(len, Ordering::SeqCst);
+        self.notify(Change::Added { len });
+    }
+    fn notify(&self, change: Change) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .for_each(|f| f(change));
+    }
+}
+
+impl WatchFolder {
+    /// pattern: "dir/frame_{}.obj"のように番号の位置を{}で示す
+    /// start: 0番目のフレームのファイル番号
+    pub fn open(pattern: &str, start: usize) -> io::Result<Self> {
+        let (prefix, suffix) = pattern
+            .split_once("{}")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "pattern requires {}"))?;
+        let shared = Arc::new(Shared {
+            prefix: prefix.to_owned(),
+            suffix: suffix.to_owned(),
+            start,
+            len: AtomicUsize::new(0),
+            subscribers: Mutex::new(vec![]),
+        });
+        // 見落としがないように監視を始めてから数える
+        let watcher = Watcher::spawn(Arc::clone(&shared))?;
+        let len = (0..)
+            .find(|&i| !Path::new(&shared.path(i)).exists())
+            .unwrap();
+        shared.len.store(len, Ordering::SeqCst);
+        Ok(Self {
+            shared,
+            watcher: Some(watcher),
+        })
+    }
+}
+
+impl fmt::Debug for WatchFolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchFolder")
+            .field("pattern", &self.shared.path(0))
+            .field("len", &self.shared.len)
+            .finish()
+    }
+}
+
+impl FrameSource for WatchFolder {
+    fn len(&self) -> usize {
+        self.shared.len.load(Ordering::SeqCst)
+    }
+    fn name(&self, index: usize) -> String {
+        self.shared.path(index)
+    }
+    fn watch(&self, notify: Notify) {
+        self.shared.subscribers.lock().unwrap().push(notify);
+    }
+    fn read(&self, index: usize, mut buf: Buffer) -> Result<Buffer> {
+        File::open(self.shared.path(index))?.read_to_end(buf.as_mut())?;
+        Ok(buf)
+    }
+}
+
+impl Drop for WatchFolder {
+    fn drop(&mut self) {
+        if let Some(w) = self.watcher.take() {
+            w.stop();
+        }
+    }
+}
+
+/// 書き込みを終えたファイルと移動してきたファイルを通知させる
+#[cfg(target_os = "linux")]
+struct Watcher {
+    fd: libc::c_int,
+    wd: libc::c_int,
+    handle: thread::JoinHandle<()>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn spawn(shared: Arc<Shared>) -> io::Result<Self> {
+        use std::ffi::CString;
+        let dir = match shared.dir() {
+            "" => ".",
+            dir => dir,
+        };
+        let dir = CString::new(dir)?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        let wd = unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let handle = thread::spawn(move || Self::run(fd, shared));
+        Ok(Self { fd, wd, handle })
+    }
+    /// 監視を外すとIN_IGNOREDが届いてスレッドが終わる
+    fn stop(self) {
+        unsafe { libc::inotify_rm_watch(self.fd, self.wd) };
+        let _ = self.handle.join();
+        unsafe { libc::close(self.fd) };
+    }
+    fn run(fd: libc::c_int, shared: Arc<Shared>) {
+        // inotify_eventの境界に揃える
+        let mut buf = [0u64; 512];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, 4096) };
+            if n < 0 {
+                match io::Error::last_os_error().kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return,
+                }
+            }
+            let bytes =
+                unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n as usize) };
+            let mut rest = bytes;
+            // wd, mask, cookie, len (u32 * 4)の後にNUL埋めされた名前が続く
+            while rest.len() >= 16 {
+                let mask = u32::from_ne_bytes(rest[4..8].try_into().unwrap());
+                let len = u32::from_ne_bytes(rest[12..16].try_into().unwrap()) as usize;
+                let name = &rest[16..16 + len];
+                rest = &rest[16 + len..];
+                if mask & libc::IN_IGNORED != 0 {
+                    return;
+                }
+                let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                if let Some(i) = std::str::from_utf8(&name[..end])
+                    .ok()
+                    .and_then(|name| shared.index_of(name))
+                {
+                    shared.changed(i);
+                }
+            }
+        }
+    }
+}
+
+/// 更新時刻を定期的に比べる
+#[cfg(not(target_os = "linux"))]
+struct Watcher {
+    stop: Arc<std::sync::atomic::AtomicBool>,
+    handle: thread::JoinHandle<()>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+    fn spawn(shared: Arc<Shared>) -> io::Result<Self> {
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let s = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            let modified = |i| {
+                std::fs::metadata(shared.path(i))
+                    .and_then(|m| m.modified())
+                    .ok()
+            };
+            let mut times = vec![];
+            while !s.load(Ordering::SeqCst) {
+                let len = shared.len.load(Ordering::SeqCst);
+                times.resize(len, None);
+                for (i, t) in times.iter_mut().enumerate() {
+                    let now = modified(i);
+                    if t.is_some() && now != *t {
+                        shared.changed(i);
+                    }
+                    *t = now;
+                }
+                shared.changed(len);
+                thread::sleep(Self::INTERVAL);
+            }
+        });
+        Ok(Self { stop, handle })
+    }
+    fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("asyncfileio_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |i: usize, s: &str| std::fs::write(dir.join(format!("f{}.txt", i)), s).unwrap();
+        write(1, "1");
+        write(2, "2");
+        let pattern = format!("{}/f{{}}.txt", dir.display());
+        let source = WatchFolder::open(&pattern, 1).unwrap();
+        assert_eq!(source.len(), 2);
+        let (tx, rx) = mpsc::channel();
+        source.watch(Box::new(move |c| tx.send(c).unwrap()));
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        write(3, "3");
+        assert_eq!(next(), Change::Added { len: 3 });
+        // 間が埋まるまでは伸ばさない
+        write(5, "5");
+        write(4, "4");
+        assert_eq!(next(), Change::Added { len: 5 });
+        assert_eq!(source.len(), 5);
+        write(2, "two");
+        assert_eq!(next(), Change::Modified(1));
+        // 名前の合わないファイルは無視する
+        write(0, "0");
+        std::fs::write(dir.join("f06.txt"), "6").unwrap();
+        std::fs::write(dir.join("g6.txt"), "6").unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+        let pool = crate::BufPool::default();
+        assert_eq!(&source.read(1, pool.get_buffer()).unwrap()[..], b"two");
+        drop(source);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
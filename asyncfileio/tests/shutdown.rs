@@ -0,0 +1,51 @@
+//! スレッド数を数えるので，他のテストと並行しないように別のプロセスで実行する
+#![cfg(target_os = "linux")]
+use anyhow::Result;
+use asyncfileio::{AsyncFileReader, Buffer, Paths, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+fn decode(buf: Buffer) -> Result<usize> {
+    // 破棄する時にデコード中のものがあっても終了を待つ
+    thread::sleep(Duration::from_millis(1));
+    Ok(buf.len())
+}
+
+#[test]
+fn test_drop_joins_threads() {
+    let dir = std::env::temp_dir().join(format!("asyncfileio_shutdown_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Paths = (0..4)
+        .map(|i| {
+            let p = dir.join(format!("{}.txt", i));
+            std::fs::write(&p, "frame").unwrap();
+            p.to_string_lossy().into_owned()
+        })
+        .collect();
+    let paths = Arc::new(paths);
+    let before = threads();
+    for i in 0..100 {
+        let mut reader = AsyncFileReader::spawn(Arc::clone(&paths), decode);
+        // 半分は受信してから，半分は読み込み中に破棄する
+        if i % 2 == 0 {
+            let start = Instant::now();
+            loop {
+                match reader.try_recv() {
+                    Ok(res) => break assert_eq!(res.unwrap().1, 5),
+                    Err(TryRecvError::Empty) if start.elapsed() < Duration::from_secs(5) => {
+                        thread::sleep(Duration::from_millis(1))
+                    }
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        }
+        drop(reader);
+    }
+    assert_eq!(threads(), before);
+    std::fs::remove_dir_all(dir).unwrap();
+}
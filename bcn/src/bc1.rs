@@ -0,0 +1,75 @@
+use super::{endpoints, Block};
+
+fn to565(c: [f32; 3]) -> u16 {
+    let q = |v: f32, max: f32| (v / 255.0 * max).round() as u16;
+    q(c[0], 31.0) << 11 | q(c[1], 63.0) << 5 | q(c[2], 31.0)
+}
+
+fn from565(c: u16) -> [u8; 4] {
+    let r = (c >> 11 & 31) as u8;
+    let g = (c >> 5 & 63) as u8;
+    let b = (c & 31) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2, 255]
+}
+
+fn mix(a: [u8; 4], b: [u8; 4], wa: u16, wb: u16) -> [u8; 4] {
+    let f = |x: u8, y: u8| ((x as u16 * wa + y as u16 * wb) / (wa + wb)) as u8;
+    [f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2]), 255]
+}
+
+/// 2つの代表色から色パレットを作る
+/// four_colorがfalseならc0 <= c1の時に3色+透明のモードになる
+fn palette(c0: u16, c1: u16, four_color: bool) -> [[u8; 4]; 4] {
+    let (e0, e1) = (from565(c0), from565(c1));
+    if four_color || c0 > c1 {
+        [e0, e1, mix(e0, e1, 2, 1), mix(e0, e1, 1, 2)]
+    } else {
+        [e0, e1, mix(e0, e1, 1, 1), [0; 4]]
+    }
+}
+
+/// 色部分 (8byte) の圧縮
+/// BC3の色部分と共通なので常に4色モードで符号化する
+pub(crate) fn encode_color(px: &Block, dst: &mut [u8]) {
+    let pts = px.map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]);
+    let (e0, e1) = endpoints(&pts);
+    let (mut c0, mut c1) = (to565(e0), to565(e1));
+    if c0 < c1 {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+    let mut indices = 0u32;
+    if c0 != c1 {
+        let pal = palette(c0, c1, true);
+        for (i, p) in px.iter().enumerate() {
+            let best = (0..4)
+                .min_by_key(|&j| {
+                    (0..3)
+                        .map(|c| (p[c] as i32 - pal[j][c] as i32).pow(2))
+                        .sum::<i32>()
+                })
+                .unwrap();
+            indices |= (best as u32) << (2 * i);
+        }
+    }
+    dst[0..2].copy_from_slice(&c0.to_le_bytes());
+    dst[2..4].copy_from_slice(&c1.to_le_bytes());
+    dst[4..8].copy_from_slice(&indices.to_le_bytes());
+}
+
+pub(crate) fn decode_color(src: &[u8], px: &mut Block, four_color: bool) {
+    let c0 = u16::from_le_bytes([src[0], src[1]]);
+    let c1 = u16::from_le_bytes([src[2], src[3]]);
+    let indices = u32::from_le_bytes([src[4], src[5], src[6], src[7]]);
+    let pal = palette(c0, c1, four_color);
+    for (i, p) in px.iter_mut().enumerate() {
+        *p = pal[(indices >> (2 * i) & 3) as usize];
+    }
+}
+
+pub(crate) fn encode_block(px: &Block, dst: &mut [u8]) {
+    encode_color(px, dst);
+}
+
+pub(crate) fn decode_block(src: &[u8], px: &mut Block) {
+    decode_color(src, px, false);
+}
@@ -0,0 +1,57 @@
+use super::{bc1, Block};
+
+/// アルファ部分の8段階(a0 > a1)または6段階+0/255のパレット
+fn palette(a0: u8, a1: u8) -> [u8; 8] {
+    let (x, y) = (a0 as u16, a1 as u16);
+    let mut pal = [a0, a1, 0, 0, 0, 0, 0, 255];
+    if a0 > a1 {
+        for (i, v) in pal.iter_mut().enumerate().skip(2) {
+            let w = i as u16 - 1;
+            *v = (((7 - w) * x + w * y) / 7) as u8;
+        }
+    } else {
+        for (i, v) in pal.iter_mut().enumerate().take(6).skip(2) {
+            let w = i as u16 - 1;
+            *v = (((5 - w) * x + w * y) / 5) as u8;
+        }
+    }
+    pal
+}
+
+fn encode_alpha(px: &Block, dst: &mut [u8]) {
+    let a0 = px.iter().map(|p| p[3]).max().unwrap();
+    let a1 = px.iter().map(|p| p[3]).min().unwrap();
+    let mut indices = 0u64;
+    if a0 != a1 {
+        let pal = palette(a0, a1);
+        for (i, p) in px.iter().enumerate() {
+            let best = (0..8)
+                .min_by_key(|&j| (p[3] as i32 - pal[j] as i32).abs())
+                .unwrap();
+            indices |= (best as u64) << (3 * i);
+        }
+    }
+    dst[0] = a0;
+    dst[1] = a1;
+    dst[2..8].copy_from_slice(&indices.to_le_bytes()[..6]);
+}
+
+fn decode_alpha(src: &[u8], px: &mut Block) {
+    let pal = palette(src[0], src[1]);
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&src[2..8]);
+    let indices = u64::from_le_bytes(bytes);
+    for (i, p) in px.iter_mut().enumerate() {
+        p[3] = pal[(indices >> (3 * i) & 7) as usize];
+    }
+}
+
+pub(crate) fn encode_block(px: &Block, dst: &mut [u8]) {
+    encode_alpha(px, &mut dst[..8]);
+    bc1::encode_color(px, &mut dst[8..]);
+}
+
+pub(crate) fn decode_block(src: &[u8], px: &mut Block) {
+    bc1::decode_color(&src[8..], px, true);
+    decode_alpha(&src[..8], px);
+}
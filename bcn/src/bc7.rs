@@ -0,0 +1,123 @@
+//! BC7はmode 6 (1サブセット, RGBA 7bit+pビット, 4bitインデックス) のみ扱う
+use super::{endpoints, Block};
+
+const WEIGHTS: [u16; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// LSBから順にビットを詰める
+struct BitWriter<'a> {
+    dst: &'a mut [u8],
+    pos: usize,
+}
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, bits: usize) {
+        for i in 0..bits {
+            if value >> i & 1 == 1 {
+                self.dst[self.pos / 8] |= 1 << (self.pos % 8);
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+impl BitReader<'_> {
+    fn read(&mut self, bits: usize) -> u32 {
+        let mut value = 0;
+        for i in 0..bits {
+            value |= ((self.src[self.pos / 8] >> (self.pos % 8) & 1) as u32) << i;
+            self.pos += 1;
+        }
+        value
+    }
+}
+
+fn interpolate(e0: [u8; 4], e1: [u8; 4], index: usize) -> [u8; 4] {
+    let w = WEIGHTS[index];
+    let f = |a: u8, b: u8| (((64 - w) * a as u16 + w * b as u16 + 32) >> 6) as u8;
+    [
+        f(e0[0], e1[0]),
+        f(e0[1], e1[1]),
+        f(e0[2], e1[2]),
+        f(e0[3], e1[3]),
+    ]
+}
+
+/// 端点を7bit+pビットに量子化する
+/// pビットは誤差の小さい方を選ぶ
+fn quantize(e: [f32; 4]) -> ([u8; 4], u8) {
+    (0..2u8)
+        .map(|p| {
+            let q = e.map(|v| ((v - p as f32) / 2.0).round().clamp(0.0, 127.0) as u8);
+            let err: f32 = (0..4)
+                .map(|c| ((q[c] << 1 | p) as f32 - e[c]).powi(2))
+                .sum();
+            (q, p, err)
+        })
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(q, p, _)| (q, p))
+        .unwrap()
+}
+
+fn unquantize(q: [u8; 4], p: u8) -> [u8; 4] {
+    q.map(|v| v << 1 | p)
+}
+
+pub(crate) fn encode_block(px: &Block, dst: &mut [u8]) {
+    let pts = px.map(|p| p.map(|v| v as f32));
+    let (e0, e1) = endpoints(&pts);
+    let (mut q0, mut p0) = quantize(e0);
+    let (mut q1, mut p1) = quantize(e1);
+    let (u0, u1) = (unquantize(q0, p0), unquantize(q1, p1));
+    let mut indices = px.map(|p| {
+        (0..16)
+            .min_by_key(|&j| {
+                let c = interpolate(u0, u1, j);
+                (0..4)
+                    .map(|k| (p[k] as i32 - c[k] as i32).pow(2))
+                    .sum::<i32>()
+            })
+            .unwrap()
+    });
+    // 先頭のインデックスは最上位ビットが0でなければならない
+    if indices[0] >= 8 {
+        std::mem::swap(&mut q0, &mut q1);
+        std::mem::swap(&mut p0, &mut p1);
+        indices = indices.map(|i| 15 - i);
+    }
+    dst.fill(0);
+    let mut w = BitWriter { dst, pos: 0 };
+    w.write(1 << 6, 7);
+    for c in 0..4 {
+        w.write(q0[c] as u32, 7);
+        w.write(q1[c] as u32, 7);
+    }
+    w.write(p0 as u32, 1);
+    w.write(p1 as u32, 1);
+    w.write(indices[0] as u32, 3);
+    for &i in &indices[1..] {
+        w.write(i as u32, 4);
+    }
+}
+
+/// mode 6以外のブロックは仕様の予約モードと同様に透明な黒にする
+pub(crate) fn decode_block(src: &[u8], px: &mut Block) {
+    let mut r = BitReader { src, pos: 0 };
+    if r.read(7) != 1 << 6 {
+        *px = [[0; 4]; 16];
+        return;
+    }
+    let (mut q0, mut q1) = ([0; 4], [0; 4]);
+    for c in 0..4 {
+        q0[c] = r.read(7) as u8;
+        q1[c] = r.read(7) as u8;
+    }
+    let e0 = unquantize(q0, r.read(1) as u8);
+    let e1 = unquantize(q1, r.read(1) as u8);
+    for (i, p) in px.iter_mut().enumerate() {
+        let index = r.read(if i == 0 { 3 } else { 4 });
+        *p = interpolate(e0, e1, index as usize);
+    }
+}
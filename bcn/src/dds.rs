@@ -0,0 +1,141 @@
+//! DDSコンテナの読み書き (ミップマップなしの2Dテクスチャのみ)
+use super::Format;
+use anyhow::{bail, ensure, Result};
+
+const MAGIC: &[u8; 4] = b"DDS ";
+const HEADER_SIZE: usize = 124;
+const DX10_SIZE: usize = 20;
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_LINEARSIZE: u32 = 0x80000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+
+const DXGI_FORMAT_BC1_UNORM: u32 = 71;
+const DXGI_FORMAT_BC3_UNORM: u32 = 77;
+const DXGI_FORMAT_BC7_UNORM: u32 = 98;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+
+/// ブロック圧縮済みのテクスチャ
+#[derive(Debug, Clone)]
+pub struct Dds {
+    pub format: Format,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Dds {
+    /// RGBA8の画像を圧縮して作る
+    pub fn encode(format: Format, width: usize, height: usize, rgba: &[u8]) -> Self {
+        Self {
+            format,
+            width,
+            height,
+            data: super::encode(format, width, height, rgba),
+        }
+    }
+    /// RGBA8に展開する
+    pub fn decode(&self) -> Vec<u8> {
+        super::decode(self.format, self.width, self.height, &self.data)
+    }
+    /// BC1/BC3はFourCC, BC7はDX10拡張ヘッダで書き出す
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let dx10 = self.format == Format::Bc7;
+        let mut v = Vec::with_capacity(4 + HEADER_SIZE + DX10_SIZE + self.data.len());
+        let mut put = |x: u32| v.extend_from_slice(&x.to_le_bytes());
+        put(u32::from_le_bytes(*MAGIC));
+        put(HEADER_SIZE as u32);
+        put(DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_LINEARSIZE);
+        put(self.height as u32);
+        put(self.width as u32);
+        put(self.data.len() as u32);
+        put(0); // depth
+        put(0); // mipmap count
+        (0..11).for_each(|_| put(0));
+        // pixel format
+        put(32);
+        put(DDPF_FOURCC);
+        put(u32::from_le_bytes(match self.format {
+            Format::Bc1 => *b"DXT1",
+            Format::Bc3 => *b"DXT5",
+            Format::Bc7 => *b"DX10",
+        }));
+        (0..5).for_each(|_| put(0));
+        // caps
+        put(DDSCAPS_TEXTURE);
+        (0..4).for_each(|_| put(0));
+        if dx10 {
+            put(DXGI_FORMAT_BC7_UNORM);
+            put(D3D10_RESOURCE_DIMENSION_TEXTURE2D);
+            put(0);
+            put(1);
+            put(0);
+        }
+        v.extend_from_slice(&self.data);
+        v
+    }
+    /// 先頭のミップレベルだけを読む
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        ensure!(is_dds(buf), "not a dds file");
+        ensure!(buf.len() >= 4 + HEADER_SIZE, "truncated dds header");
+        let get = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let height = get(12) as usize;
+        let width = get(16) as usize;
+        let flags = get(80);
+        ensure!(
+            flags & DDPF_FOURCC != 0,
+            "uncompressed dds is not supported"
+        );
+        let mut offset = 4 + HEADER_SIZE;
+        let format = match &get(84).to_le_bytes() {
+            b"DXT1" => Format::Bc1,
+            b"DXT5" => Format::Bc3,
+            b"DX10" => {
+                ensure!(buf.len() >= offset + DX10_SIZE, "truncated dx10 header");
+                let dxgi = get(offset);
+                offset += DX10_SIZE;
+                match dxgi {
+                    DXGI_FORMAT_BC1_UNORM => Format::Bc1,
+                    DXGI_FORMAT_BC3_UNORM => Format::Bc3,
+                    DXGI_FORMAT_BC7_UNORM => Format::Bc7,
+                    _ => bail!("unsupported dxgi format: {}", dxgi),
+                }
+            }
+            fourcc => bail!("unsupported fourcc: {:?}", fourcc),
+        };
+        let size = format.compressed_size(width, height);
+        ensure!(buf.len() >= offset + size, "truncated dds data");
+        Ok(Self {
+            format,
+            width,
+            height,
+            data: buf[offset..offset + size].to_vec(),
+        })
+    }
+}
+
+/// 先頭のマジックナンバーでDDSか判定する
+pub fn is_dds(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_roundtrip() {
+        let rgba: Vec<u8> = (0..12 * 8 * 4).map(|i| (i * 7 % 256) as u8).collect();
+        for format in [Format::Bc1, Format::Bc3, Format::Bc7] {
+            let dds = Dds::encode(format, 12, 8, &rgba);
+            let read = Dds::from_bytes(&dds.to_bytes()).unwrap();
+            assert_eq!(read.format, format);
+            assert_eq!((read.width, read.height), (12, 8));
+            assert_eq!(read.data, dds.data);
+        }
+        assert!(Dds::from_bytes(b"PNG garbage").is_err());
+    }
+}
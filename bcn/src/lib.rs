@@ -0,0 +1,228 @@
+//! テクスチャのブロック圧縮 (BC1/BC3/BC7) の純Rust実装
+//! 4x4ピクセルを1ブロックとして固定長に圧縮する
+mod bc1;
+mod bc3;
+mod bc7;
+pub mod dds;
+
+pub use dds::Dds;
+
+/// 1ブロックのピクセル (RGBA8, 行優先)
+type Block = [[u8; 4]; 16];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// RGB 4bpp (アルファなし)
+    Bc1,
+    /// RGBA 8bpp (補間アルファ)
+    Bc3,
+    /// RGBA 8bpp (高品質, mode 6のみ使用)
+    Bc7,
+}
+
+impl Format {
+    /// 1ブロックあたりのバイト数
+    pub fn block_size(self) -> usize {
+        match self {
+            Format::Bc1 => 8,
+            Format::Bc3 | Format::Bc7 => 16,
+        }
+    }
+    /// 圧縮後のバイト数
+    pub fn compressed_size(self, width: usize, height: usize) -> usize {
+        blocks(width) * blocks(height) * self.block_size()
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bc1" | "dxt1" => Ok(Format::Bc1),
+            "bc3" | "dxt5" => Ok(Format::Bc3),
+            "bc7" => Ok(Format::Bc7),
+            _ => anyhow::bail!("unknown block format: {}", s),
+        }
+    }
+}
+
+fn blocks(n: usize) -> usize {
+    n.div_ceil(4)
+}
+
+/// RGBA8の画像をブロック圧縮する
+/// 4の倍数でない端は端のピクセルを複製して埋める
+pub fn encode(format: Format, width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(rgba.len(), width * height * 4, "rgba size mismatch");
+    let mut out = vec![0; format.compressed_size(width, height)];
+    let mut chunks = out.chunks_exact_mut(format.block_size());
+    for by in 0..blocks(height) {
+        for bx in 0..blocks(width) {
+            let mut px = [[0; 4]; 16];
+            for (i, p) in px.iter_mut().enumerate() {
+                let x = (bx * 4 + i % 4).min(width - 1);
+                let y = (by * 4 + i / 4).min(height - 1);
+                let o = (y * width + x) * 4;
+                p.copy_from_slice(&rgba[o..o + 4]);
+            }
+            let dst = chunks.next().unwrap();
+            match format {
+                Format::Bc1 => bc1::encode_block(&px, dst),
+                Format::Bc3 => bc3::encode_block(&px, dst),
+                Format::Bc7 => bc7::encode_block(&px, dst),
+            }
+        }
+    }
+    out
+}
+
+/// ブロック圧縮された画像をRGBA8に展開する
+pub fn decode(format: Format, width: usize, height: usize, data: &[u8]) -> Vec<u8> {
+    assert_eq!(
+        data.len(),
+        format.compressed_size(width, height),
+        "block data size mismatch"
+    );
+    let mut out = vec![0; width * height * 4];
+    let mut chunks = data.chunks_exact(format.block_size());
+    for by in 0..blocks(height) {
+        for bx in 0..blocks(width) {
+            let mut px = [[0; 4]; 16];
+            let src = chunks.next().unwrap();
+            match format {
+                Format::Bc1 => bc1::decode_block(src, &mut px),
+                Format::Bc3 => bc3::decode_block(src, &mut px),
+                Format::Bc7 => bc7::decode_block(src, &mut px),
+            }
+            for (i, p) in px.iter().enumerate() {
+                let (x, y) = (bx * 4 + i % 4, by * 4 + i / 4);
+                if x < width && y < height {
+                    let o = (y * width + x) * 4;
+                    out[o..o + 4].copy_from_slice(p);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// 2つの画像のPSNR(dB)
+/// 完全に一致する場合は無限大
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len());
+    let se: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum();
+    let mse = se / a.len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// 点群の平均と主軸 (べき乗法)
+fn principal_axis<const N: usize>(pts: &[[f32; N]; 16]) -> ([f32; N], [f32; N]) {
+    let mut mean = [0.0; N];
+    for p in pts {
+        for c in 0..N {
+            mean[c] += p[c] / 16.0;
+        }
+    }
+    let mut cov = [[0.0f32; N]; N];
+    for p in pts {
+        for i in 0..N {
+            for j in 0..N {
+                cov[i][j] += (p[i] - mean[i]) * (p[j] - mean[j]);
+            }
+        }
+    }
+    let mut axis = [1.0; N];
+    for _ in 0..8 {
+        let mut next = [0.0; N];
+        for i in 0..N {
+            for j in 0..N {
+                next[i] += cov[i][j] * axis[j];
+            }
+        }
+        let norm = next.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm < f32::EPSILON {
+            break;
+        }
+        axis = next.map(|v| v / norm);
+    }
+    (mean, axis)
+}
+
+/// 主軸に沿った両端点
+fn endpoints<const N: usize>(pts: &[[f32; N]; 16]) -> ([f32; N], [f32; N]) {
+    let (mean, axis) = principal_axis(pts);
+    let dot = |p: &[f32; N]| (0..N).map(|c| (p[c] - mean[c]) * axis[c]).sum::<f32>();
+    let (mut lo, mut hi) = (f32::MAX, f32::MIN);
+    for p in pts {
+        let t = dot(p);
+        lo = lo.min(t);
+        hi = hi.max(t);
+    }
+    let at = |t: f32| {
+        let mut e = [0.0; N];
+        for c in 0..N {
+            e[c] = (mean[c] + axis[c] * t).clamp(0.0, 255.0);
+        }
+        e
+    };
+    (at(hi), at(lo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        let mut v = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                v.push((x * 255 / width) as u8);
+                v.push((y * 255 / height) as u8);
+                v.push(((x + y) * 127 / (width + height)) as u8);
+                v.push((255 - x * 128 / width) as u8);
+            }
+        }
+        v
+    }
+
+    fn opaque(mut v: Vec<u8>) -> Vec<u8> {
+        v.iter_mut().skip(3).step_by(4).for_each(|a| *a = 255);
+        v
+    }
+
+    #[test]
+    fn test_psnr() {
+        for (format, (w, h), min) in [
+            (Format::Bc1, (64, 64), 35.0),
+            (Format::Bc3, (64, 64), 35.0),
+            (Format::Bc7, (64, 64), 38.0),
+            // 勾配が急なので低め
+            (Format::Bc7, (30, 18), 30.0),
+        ] {
+            let src = gradient(w, h);
+            let src = if format == Format::Bc1 {
+                opaque(src)
+            } else {
+                src
+            };
+            let enc = encode(format, w, h, &src);
+            assert_eq!(enc.len(), format.compressed_size(w, h));
+            let dec = decode(format, w, h, &enc);
+            let db = psnr(&src, &dec);
+            assert!(db > min, "{:?} {}x{}: {} dB", format, w, h, db);
+        }
+    }
+
+    #[test]
+    fn test_flat_block() {
+        let src = [10, 200, 30, 255].repeat(16);
+        for format in [Format::Bc1, Format::Bc3, Format::Bc7] {
+            let dec = decode(format, 4, 4, &encode(format, 4, 4, &src));
+            assert!(psnr(&src, &dec) > 40.0, "{:?}", format);
+        }
+    }
+}
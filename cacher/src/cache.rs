@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::rc::{Rc, Weak};
+
+/// 複数のCacherで共有できるキャッシュ
+pub type SharedCache<K, T> = Rc<RefCell<Cache<K, T>>>;
+
+/// 最近使ったものを持っておくキャッシュ
+/// 手放しても他で使われている間は取り出せる
+pub struct Cache<K, T> {
+    map: HashMap<K, Entry<T>>,
+    /// 最近使った順の(キー, 値, 予算ごとの大きさ) (世代が大きいほど新しく，同じキーは1つだけ)
+    que: BTreeMap<u64, (K, Rc<T>, Vec<usize>)>,
+    /// 次に使う世代
+    generation: u64,
+    budgets: Vec<Budget<T>>,
+    pub que_max: usize,
+}
+
+/// 値の一部 (頂点とテクスチャなど) ごとのメモリ量の上限
+struct Budget<T> {
+    max_bytes: usize,
+    size_of: fn(&T) -> usize,
+    /// queが持つ値の大きさの合計
+    bytes: usize,
+}
+
+struct Entry<T> {
+    value: Weak<T>,
+    /// queでの世代 (手放していればNone)
+    generation: Option<u64>,
+}
+
+impl<K: Hash + Eq + Clone, T> Cache<K, T> {
+    pub fn new(que_max: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            que: BTreeMap::new(),
+            generation: 0,
+            budgets: vec![],
+            que_max,
+        }
+    }
+    /// 持っている値の大きさの合計をmax_bytes以下に抑える
+    /// size_of: 値が使っているメモリの量
+    /// 値の部分ごとに別々の予算を付ける時は続けて指定する
+    /// que_maxと全ての予算を満たすように古いものから手放すが，最も新しいものは手放さない
+    pub fn max_bytes(mut self, max_bytes: usize, size_of: fn(&T) -> usize) -> Self {
+        self.budgets.push(Budget {
+            max_bytes,
+            size_of,
+            bytes: 0,
+        });
+        self
+    }
+    pub fn shared(self) -> SharedCache<K, T> {
+        Rc::new(RefCell::new(self))
+    }
+    /// 持っている値の大きさの合計 (全ての予算の分)
+    pub fn bytes(&self) -> usize {
+        self.budgets.iter().map(|b| b.bytes).sum()
+    }
+    /// max_bytesで指定した順のi番目の予算で数えた合計
+    pub fn bytes_of(&self, i: usize) -> usize {
+        self.budgets[i].bytes
+    }
+    /// 持っている値の数
+    pub fn len(&self) -> usize {
+        self.que.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.que.is_empty()
+    }
+    /// あれば最も新しく使ったものにして返す
+    pub fn get(&mut self, key: &K) -> Option<Rc<T>> {
+        let v = self.map.get(key)?.value.upgrade()?;
+        self.touch(key.clone(), Rc::clone(&v));
+        Some(v)
+    }
+    pub fn insert(&mut self, key: K, v: T) -> Rc<T> {
+        let value = Rc::new(v);
+        self.release(&key);
+        let entry = Entry {
+            value: Rc::downgrade(&value),
+            generation: None,
+        };
+        self.map.insert(key.clone(), entry);
+        self.touch(key, Rc::clone(&value));
+        // 他でも使われずに消えた値の分を時々片付ける
+        if self.map.len() > 2 * self.que.len().max(16) {
+            self.map
+                .retain(|_, e| e.generation.is_some() || e.value.strong_count() > 0);
+        }
+        value
+    }
+    /// 内容が変わったものを捨てる
+    pub fn remove(&mut self, key: &K) {
+        self.release(key);
+        self.map.remove(key);
+    }
+    /// 溢れたら最も古いものを手放す
+    /// keyはmapにあること
+    fn touch(&mut self, key: K, v: Rc<T>) {
+        self.release(&key);
+        let sizes: Vec<_> = self.budgets.iter().map(|b| (b.size_of)(&v)).collect();
+        self.charge(&sizes, true);
+        let generation = self.generation;
+        self.generation += 1;
+        self.map.get_mut(&key).unwrap().generation = Some(generation);
+        self.que.insert(generation, (key, v, sizes));
+        while self.que.len() > self.que_max || (self.que.len() > 1 && self.over_budget()) {
+            let (_, (key, v, sizes)) = self.que.pop_first().unwrap();
+            self.charge(&sizes, false);
+            if Rc::strong_count(&v) == 1 {
+                self.map.remove(&key);
+            } else if let Some(e) = self.map.get_mut(&key) {
+                e.generation = None;
+            }
+        }
+    }
+    fn release(&mut self, key: &K) {
+        let generation = self.map.get_mut(key).and_then(|e| e.generation.take());
+        if let Some((_, _, sizes)) = generation.and_then(|g| self.que.remove(&g)) {
+            self.charge(&sizes, false);
+        }
+    }
+    /// 予算ごとの合計に足す (addでなければ引く)
+    fn charge(&mut self, sizes: &[usize], add: bool) {
+        for (b, size) in self.budgets.iter_mut().zip(sizes) {
+            match add {
+                true => b.bytes += size,
+                false => b.bytes -= size,
+            }
+        }
+    }
+    fn over_budget(&self) -> bool {
+        self.budgets.iter().any(|b| b.bytes > b.max_bytes)
+    }
+    /// 古い順のキー
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.que.values().map(|(k, ..)| k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let mut cache = Cache::new(3).max_bytes(9, |s: &String| s.len());
+        for key in ["a", "b", "c"] {
+            cache.insert(key, key.repeat(2));
+        }
+        assert_eq!(cache.get(&"a").as_deref().map(|s| s.as_str()), Some("aa"));
+        let b = cache.get(&"b").unwrap();
+        cache.insert("d", "dd".into());
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [&"a", &"b", &"d"]);
+        assert!(cache.get(&"c").is_none());
+        // 予算を超えたら古いものから手放す
+        cache.insert("e", "eeeeee".into());
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [&"d", &"e"]);
+        assert_eq!(cache.bytes(), 8);
+        // 手放しても使われていれば取り出せる
+        assert_eq!(*cache.get(&"b").unwrap(), *b);
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [&"e", &"b"]);
+        cache.remove(&"b");
+        assert!(cache.get(&"b").is_none());
+        assert_eq!((cache.len(), cache.bytes()), (1, 6));
+        // 同じキーで入れ直すと置き換える
+        cache.insert("e", "ee".into());
+        assert_eq!((cache.len(), cache.bytes()), (1, 2));
+    }
+
+    #[test]
+    fn test_budgets() {
+        // (頂点, テクスチャ) のうち頂点にだけ上限を付ける
+        let mut cache = Cache::new(usize::MAX)
+            .max_bytes(4, |v: &(String, String)| v.0.len())
+            .max_bytes(usize::MAX, |v| v.1.len());
+        for key in 0..4 {
+            cache.insert(key, ("v".into(), "t".repeat(100)));
+        }
+        // テクスチャが大きくても頂点の上限では手放さない
+        assert_eq!(cache.len(), 4);
+        assert_eq!((cache.bytes_of(0), cache.bytes_of(1)), (4, 400));
+        cache.insert(4, ("vv".into(), "t".into()));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), [&2, &3, &4]);
+        assert_eq!((cache.bytes_of(0), cache.bytes_of(1)), (4, 201));
+        assert_eq!(cache.bytes(), 205);
+    }
+}
@@ -1,9 +1,18 @@
+use anyhow::Result;
 pub use asyncfileio::Msg;
-use asyncfileio::{AsyncFileReader, BufPool, Buffer, Paths};
-use std::collections::VecDeque;
+use asyncfileio::{
+    AsyncFileReader, BufPool, Buffer, Change, Metrics, ReadError, RecvTimeoutError, Source,
+    TryRecvError,
+};
+pub use cache::{Cache, SharedCache};
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::marker::PhantomData;
-use std::rc::{Rc, Weak};
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+mod cache;
 
 pub struct Decoder<T, U, F>
 where
@@ -32,75 +41,487 @@ where
         (self.f)(buf, &mut self.pool)
     }
 }
-pub struct Cacher<T, U: 'static + Send, F: FnMut(U, &mut BufPool) -> T> {
+/// 読み込みスレッドから受け取ったフレームをキャッシュに入れて取り出す
+/// K: フレームを指すキー (他の連番とキャッシュを共有する時に区別する)
+pub struct Cacher<T, U: 'static + Send, F: FnMut(U, &mut BufPool) -> T, K = usize> {
     reader: AsyncFileReader<U>,
-    map: Vec<Option<Weak<T>>>,
-    que: VecDeque<Rc<T>>,
+    cache: SharedCache<K, T>,
+    /// フレーム番号 -> キー
+    key_of: Box<dyn Fn(usize) -> K>,
+    /// キー -> (フレーム番号, ペイロード)
+    frames: HashMap<K, (usize, usize)>,
+    /// ペイロード -> キャッシュに入れる時のキー
+    /// 同じ内容のフレームは最初のフレームのキーで値を共有する
+    slots: HashMap<usize, K>,
+    /// 読み込みに失敗したもの (キャッシュに入れる時のキーごと)
+    errors: HashMap<K, ReadError>,
+    /// フレーム数
+    len: usize,
     decoder: Decoder<T, U, F>,
-    pub que_max: usize,
-    _paths: Arc<Paths>,
+    source: Source,
+    /// 読み込み元の変化 (反映済みのものはchangedに貯める)
+    changes: mpsc::Receiver<Change>,
+    changed: Vec<Change>,
 }
 
 impl<T, U: 'static + Send, F: FnMut(U, &mut BufPool) -> T> Cacher<T, U, F> {
+    /// フレーム番号をキーにする
     /// f: ブロッキングスレッドで実行する
     /// g: イベントループで実行する
-    pub fn new(que_max: usize, paths: Arc<Paths>, f: fn(Buffer) -> U, g: F) -> Self {
-        let len = paths.len();
-        Self {
-            reader: AsyncFileReader::spawn(Arc::clone(&paths), f),
-            map: vec![None; len],
-            que: VecDeque::new(),
+    pub fn new(
+        que_max: usize,
+        source: impl Into<Source>,
+        f: fn(Buffer) -> Result<U>,
+        g: F,
+    ) -> Self {
+        Self::with_cache(Cache::new(que_max).shared(), |i| i, source, f, g)
+    }
+}
+
+impl<T, U, F, K> Cacher<T, U, F, K>
+where
+    U: 'static + Send,
+    F: FnMut(U, &mut BufPool) -> T,
+    K: Hash + Eq + Clone,
+{
+    /// 他のCacherとキャッシュ (と予算) を共有する
+    /// key_of: フレーム番号からキーを作る (他のCacherと重ならないようにする)
+    pub fn with_cache(
+        cache: SharedCache<K, T>,
+        key_of: impl Fn(usize) -> K + 'static,
+        source: impl Into<Source>,
+        f: fn(Buffer) -> Result<U>,
+        g: F,
+    ) -> Self {
+        let source = source.into();
+        let (tx, changes) = mpsc::channel();
+        source.watch(Box::new(move |c| {
+            let _ = tx.send(c);
+        }));
+        let mut cacher = Self {
+            reader: AsyncFileReader::spawn(source.clone(), f),
+            cache,
+            key_of: Box::new(key_of),
+            frames: HashMap::new(),
+            slots: HashMap::new(),
+            errors: HashMap::new(),
+            len: 0,
             decoder: Decoder::new(g),
-            que_max,
-            _paths: paths,
+            source,
+            changes,
+            changed: vec![],
+        };
+        cacher.extend(cacher.source.len());
+        cacher
+    }
+    /// 共有しているキャッシュ
+    pub fn cache(&self) -> &SharedCache<K, T> {
+        &self.cache
+    }
+    /// フレーム数 (読み込み元が伸びると増える)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// 前回から反映した読み込み元の変化
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.update();
+        std::mem::take(&mut self.changed)
+    }
+    /// lenまでのフレームにキーを振る
+    fn extend(&mut self, len: usize) {
+        for i in self.len..len {
+            let (key, payload) = ((self.key_of)(i), self.source.payload_of(i));
+            self.slots.entry(payload).or_insert_with(|| key.clone());
+            self.frames.insert(key, (i, payload));
+        }
+        self.len = self.len.max(len);
+    }
+    /// 増えたフレームを加え，書き換わったフレームを捨てる
+    fn update(&mut self) {
+        while let Ok(change) = self.changes.try_recv() {
+            match change {
+                Change::Added { len } => self.extend(len),
+                Change::Modified(i) => {
+                    if let Some((_, slot)) = self.slot(&(self.key_of)(i)) {
+                        self.errors.remove(&slot);
+                        self.cache.borrow_mut().remove(&slot);
+                    }
+                }
+            }
+            self.changed.push(change);
         }
     }
+    /// キーのフレーム番号とキャッシュに入れる時のキー
+    fn slot(&self, key: &K) -> Option<(usize, K)> {
+        let (index, payload) = self.frames.get(key)?;
+        Some((*index, self.slots[payload].clone()))
+    }
     /// メッセージの送信
     pub fn query(&self, msg: Msg) {
-        let _ = self.reader.tx.blocking_send(msg);
+        let _ = self.reader.send(msg);
+    }
+    /// 読み込みスレッドの統計
+    pub fn metrics(&self) -> Metrics {
+        self.reader.metrics()
     }
 
     /// キャッシュになければ非同期スレッドからの受信をを試みる
-    pub fn get(&mut self, key: usize) -> Option<Rc<T>> {
-        if let Some(Some(buf)) = self.map[key].as_ref().map(|v| v.upgrade()) {
-            return Some(buf);
+    pub fn get(&mut self, key: &K) -> Option<Rc<T>> {
+        let (_, slot) = match self.cached(key)? {
+            Ok(v) => return Some(v),
+            Err(slot) => slot,
+        };
+        self.drain(&slot).flatten()
+    }
+    /// keyのフレームが届くまで最大timeoutだけ待つ
+    /// 読み込み中でなければ読み込みを頼む
+    /// 読み込みに失敗したか時間切れならNone
+    pub fn get_blocking(&mut self, key: &K, timeout: Duration) -> Option<Rc<T>> {
+        let deadline = Instant::now() + timeout;
+        let (index, slot) = match self.cached(key)? {
+            Ok(v) => return Some(v),
+            Err(slot) => slot,
+        };
+        if let Some(v) = self.drain(&slot) {
+            return v;
         }
+        self.query(Msg::Fetch(index));
         loop {
-            let (k, vv) = match self.reader.rx.try_recv() {
-                Ok(Ok(x)) => x,
-                Err(asyncfileio::TryRecvError::Empty) => {
-                    return None;
-                }
-                Ok(Err(e)) => {
-                    dbg!(e);
-                    return None;
-                }
-                _ => panic!("disconnected async file reader"),
+            let left = deadline.saturating_duration_since(Instant::now());
+            let res = match self.reader.recv_timeout(left) {
+                Ok(res) => res,
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => panic!("disconnected async file reader"),
             };
-            let v = self.decoder.decode(vv);
-            let is_key = k == key;
-            let buf = self.insert(k, v);
-            if is_key {
-                return Some(buf);
+            let (got, v) = self.accept(res);
+            if got == slot {
+                return v;
             }
         }
     }
-    fn insert(&mut self, k: usize, v: T) -> Rc<T> {
-        let value = Rc::new(v);
-        self.map.insert(k, Some(Rc::downgrade(&value)));
-        self.que.push_back(Rc::clone(&value));
-        while self.que.len() > self.que_max {
-            self.que.pop_front();
+    /// get_blockingの非同期版 (時間切れはtokio::time::timeoutなどで付ける)
+    pub async fn fetch(&mut self, key: &K) -> Option<Rc<T>> {
+        let (index, slot) = match self.cached(key)? {
+            Ok(v) => return Some(v),
+            Err(slot) => slot,
+        };
+        if let Some(v) = self.drain(&slot) {
+            return v;
+        }
+        self.query(Msg::Fetch(index));
+        loop {
+            let res = self
+                .reader
+                .recv()
+                .await
+                .expect("disconnected async file reader");
+            let (got, v) = self.accept(res);
+            if got == slot {
+                return v;
+            }
+        }
+    }
+    /// キャッシュにあればその値，なければフレーム番号とキャッシュに入れる時のキー
+    fn cached(&mut self, key: &K) -> Option<Result<Rc<T>, (usize, K)>> {
+        self.update();
+        let (index, slot) = self.slot(key)?;
+        match self.cache.borrow_mut().get(&slot) {
+            Some(v) => Some(Ok(v)),
+            None => Some(Err((index, slot))),
+        }
+    }
+    /// 届いているものを受け取る
+    /// slotのものが届いたらその値 (失敗ならNone) を返す
+    fn drain(&mut self, slot: &K) -> Option<Option<Rc<T>>> {
+        loop {
+            let res = match self.reader.try_recv() {
+                Ok(res) => res,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => panic!("disconnected async file reader"),
+            };
+            let (got, v) = self.accept(res);
+            if got == *slot {
+                return Some(v);
+            }
         }
-        value
+    }
+    /// 応答をキャッシュに入れ，キャッシュに入れる時のキーと値 (失敗ならNone) を返す
+    fn accept(&mut self, res: Result<(usize, U), ReadError>) -> (K, Option<Rc<T>>) {
+        // 伸びたフレームの応答が変化の通知より先に取り出されることがある
+        self.update();
+        let index = match &res {
+            Ok((index, _)) => *index,
+            Err(e) => e.index,
+        };
+        let (_, slot) = self.slot(&(self.key_of)(index)).expect("frame has a key");
+        match res {
+            Ok((_, u)) => {
+                self.errors.remove(&slot);
+                // 持っている値は置き換えずに使い回す (使っている側の値と食い違わない)
+                let cached = self.cache.borrow_mut().get(&slot);
+                let v = cached.unwrap_or_else(|| {
+                    let v = self.decoder.decode(u);
+                    self.cache.borrow_mut().insert(slot.clone(), v)
+                });
+                (slot, Some(v))
+            }
+            Err(e) => {
+                self.errors.insert(slot.clone(), e);
+                (slot, None)
+            }
+        }
+    }
+    /// 読み込みに失敗したフレームならその理由を返す
+    /// Seekで指定し直すと読み直す
+    pub fn failed(&self, key: &K) -> Option<&ReadError> {
+        let (_, slot) = self.slot(key)?;
+        self.errors.get(&slot)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::sync::Arc;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    /// 内容がフレーム番号のファイルを作る
+    fn fixture(name: &str, n: usize) -> Source {
+        let dir = std::env::temp_dir().join(format!("cacher_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (0..n)
+            .map(|i| {
+                let p = dir.join(format!("{}.txt", i));
+                std::fs::write(&p, i.to_string()).unwrap();
+                p.to_string_lossy().into_owned()
+            })
+            .collect();
+        Arc::new(paths).into()
+    }
+
+    fn parse(buf: Buffer) -> Result<usize> {
+        Ok(std::str::from_utf8(&buf)?.parse()?)
+    }
+
+    /// 届くまで待つ
+    fn wait<F: FnMut(usize, &mut BufPool) -> usize>(
+        cacher: &mut Cacher<usize, usize, F>,
+        key: usize,
+    ) -> Rc<usize> {
+        let start = Instant::now();
+        loop {
+            if let Some(v) = cacher.get(&key) {
+                return v;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "timeout: {}", key);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn slots<T, U: Send, F: FnMut(U, &mut BufPool) -> T>(cacher: &Cacher<T, U, F>) -> Vec<usize> {
+        cacher.cache().borrow().keys().copied().collect()
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut cacher = Cacher::new(4, fixture("wraparound", 10), parse, |u, _| u);
+        for key in (0..10).cycle().take(30) {
+            assert_eq!(*wait(&mut cacher, key), key);
+            // 当たっても同じ値を返す
+            assert_eq!(*cacher.get(&key).unwrap(), key);
+            let mut held = slots(&cacher);
+            assert!(held.len() <= 4);
+            assert_eq!(held.last(), Some(&key));
+            held.sort_unstable();
+            held.dedup();
+            assert_eq!(held.len(), cacher.cache().borrow().len());
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut cacher = Cacher::new(3, fixture("lru", 10), parse, |u, _| u);
+        for key in 0..3 {
+            wait(&mut cacher, key);
+        }
+        // 当たったものは最も新しくなる
+        assert_eq!(*cacher.get(&0).unwrap(), 0);
+        assert_eq!(slots(&cacher), [1, 2, 0]);
+        assert_eq!(*wait(&mut cacher, 3), 3);
+        assert_eq!(slots(&cacher), [2, 0, 3]);
+        assert!(cacher.cache().borrow_mut().get(&1).is_none());
+        // 手放した後も他で使われていれば取り出せる
+        let zero = cacher.get(&0).unwrap();
+        for key in 4..7 {
+            wait(&mut cacher, key);
+        }
+        assert_eq!(slots(&cacher), [4, 5, 6]);
+        assert_eq!(*cacher.get(&0).unwrap(), *zero);
+        assert_eq!(slots(&cacher), [5, 6, 0]);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let cache = Cache::new(usize::MAX).max_bytes(100, |v| (v + 1) * 10);
+        let source = fixture("max_bytes", 20);
+        let mut cacher = Cacher::with_cache(cache.shared(), |i| i, source, parse, |u, _| u);
+        for key in 0..4 {
+            wait(&mut cacher, key);
+        }
+        assert_eq!(
+            (slots(&cacher), cacher.cache().borrow().bytes()),
+            (vec![0, 1, 2, 3], 100)
+        );
+        // 収まるまで古いものから手放す
+        wait(&mut cacher, 4);
+        assert_eq!(
+            (slots(&cacher), cacher.cache().borrow().bytes()),
+            (vec![3, 4], 90)
+        );
+        for key in 5..20 {
+            wait(&mut cacher, key);
+            // 予算より大きい値でも最も新しいものは持っておく
+            assert_eq!(slots(&cacher), [key]);
+            assert_eq!(cacher.cache().borrow().bytes(), (key + 1) * 10);
+        }
+    }
+
+    #[test]
+    fn test_get_blocking() {
+        fn slow(buf: Buffer) -> Result<usize> {
+            let v = parse(buf)?;
+            if v == 30 {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            Ok(v)
+        }
+        let mut cacher = Cacher::new(4, fixture("blocking", 50), slow, |u, _| u);
+        // 先読みの範囲外のフレームも読みに行く
+        let v = cacher.get_blocking(&20, Duration::from_secs(5));
+        assert_eq!(v.as_deref(), Some(&20));
+        assert!(cacher
+            .get_blocking(&30, Duration::from_millis(50))
+            .is_none());
+        let v = cacher.get_blocking(&30, Duration::from_secs(5));
+        assert_eq!(v.as_deref(), Some(&30));
+        // キャッシュにあれば待たない
+        let start = Instant::now();
+        assert_eq!(
+            *cacher.get_blocking(&20, Duration::from_secs(5)).unwrap(),
+            20
+        );
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert!(cacher.get_blocking(&50, Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn test_get_blocking_paused() {
+        let mut cacher = Cacher::new(4, fixture("blocking_paused", 50), parse, |u, _| u);
+        cacher.query(Msg::Pause);
+        // 止めていても頼んだものは届き，再生位置は動かない
+        for key in [20, 40, 20, 3] {
+            let v = cacher.get_blocking(&key, Duration::from_secs(5));
+            assert_eq!(v.as_deref(), Some(&key));
+        }
+        let start = Instant::now();
+        assert_eq!(
+            *cacher.get_blocking(&40, Duration::from_secs(5)).unwrap(),
+            40
+        );
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_fetch_then_seek() {
+        fn slow(buf: Buffer) -> Result<usize> {
+            let v = parse(buf)?;
+            if v == 2 {
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            Ok(v)
+        }
+        let mut cacher = Cacher::new(4, fixture("fetch_seek", 50), slow, |u, _| u);
+        // 先読み中のフレームを頼んでから先読みを取り消しても届く
+        assert!(cacher.get_blocking(&2, Duration::from_millis(10)).is_none());
+        cacher.query(Msg::Seek {
+            index: 40,
+            flush: true,
+        });
+        cacher.query(Msg::Pause);
+        assert_eq!(*wait(&mut cacher, 2), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_fetch() {
+        let mut cacher = Cacher::new(4, fixture("fetch", 50), parse, |u, _| u);
+        cacher.query(Msg::Pause);
+        for key in [40, 3, 41, 40] {
+            assert_eq!(cacher.fetch(&key).await.as_deref(), Some(&key));
+        }
+    }
+
+    /// 名前を付けて文字列にする
+    type Naming = Box<dyn FnMut(usize, &mut BufPool) -> String>;
+    type Named = Cacher<String, usize, Naming, String>;
+
+    /// 2つの連番で1つのキャッシュと予算 (値5つ分) を共有する
+    fn shared(name: &str) -> (SharedCache<String, String>, Named, Named) {
+        let cache = Cache::new(usize::MAX)
+            .max_bytes(50, |v: &String| v.len())
+            .shared();
+        let cacher = |seq: &'static str| {
+            let named: Naming = Box::new(move |u, _| format!("{}{:09}", seq, u));
+            Cacher::with_cache(
+                Rc::clone(&cache),
+                move |i| format!("{}/{}", seq, i),
+                fixture(&format!("{}_{}", name, seq), 10),
+                parse,
+                named,
+            )
+        };
+        let (a, b) = (cacher("a"), cacher("b"));
+        (cache, a, b)
+    }
+
+    #[test]
+    fn test_shared() {
+        let (cache, mut a, mut b) = shared("shared");
+        let timeout = Duration::from_secs(5);
+        for i in (0..10).cycle().take(25) {
+            let (ka, kb) = (format!("a/{}", i), format!("b/{}", i));
+            assert_eq!(*a.get_blocking(&ka, timeout).unwrap(), format!("a{:09}", i));
+            assert_eq!(*b.get_blocking(&kb, timeout).unwrap(), format!("b{:09}", i));
+            let c = cache.borrow();
+            assert!(c.bytes() <= 50);
+            assert_eq!(c.keys().last(), Some(&kb));
+        }
+        assert!(a.get(&"b/4".to_string()).is_none());
+        assert!(a.failed(&"a/4".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_shared_paused() {
+        let (cache, mut a, mut b) = shared("shared_paused");
+        a.query(Msg::Pause);
+        b.query(Msg::Pause);
+        let timeout = Duration::from_secs(5);
+        for i in (0..10).cycle().take(25) {
+            let (ka, kb) = (format!("a/{}", i), format!("b/{}", i));
+            assert_eq!(*a.get_blocking(&ka, timeout).unwrap(), format!("a{:09}", i));
+            assert_eq!(*b.get_blocking(&kb, timeout).unwrap(), format!("b{:09}", i));
+        }
+        // 頼んだものだけが頼んだ順に残る
+        assert_eq!(
+            cache.borrow().keys().collect::<Vec<_>>(),
+            ["b/2", "a/3", "b/3", "a/4", "b/4"]
+        );
+    }
 }
@@ -1 +1,3 @@
+pub mod stats;
+pub mod transform;
 pub mod wavefrontobj;
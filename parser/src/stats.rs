@@ -0,0 +1,163 @@
+use crate::wavefrontobj::Model;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// 法線の長さの許容誤差
+const NORMAL_EPS: f32 = 1e-3;
+/// これより面積の小さい三角形は縮退とみなす
+const AREA_EPS: f32 = 1e-12;
+
+/// 1フレーム分の検査結果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameStats {
+    pub vertices: usize,
+    pub triangles: usize,
+    /// 有限な座標のみから求めた(min, max)
+    pub bbox: Option<([f32; 3], [f32; 3])>,
+    /// 同じ頂点を含むか面積がほぼ0の三角形
+    pub degenerate: usize,
+    /// 範囲外のインデックスやp/t/n形式でない三角形
+    pub invalid_faces: usize,
+    /// NaNやInfを含む座標
+    pub non_finite: usize,
+    /// 長さが1でない法線
+    pub unnormalized: usize,
+    /// [0, 1]の外にあるUV
+    pub uv_out_of_range: usize,
+    /// 面が参照する座標インデックス列のハッシュ
+    pub topology: u64,
+}
+
+/// 1始まり/負値のインデックスを0始まりに直す
+fn resolve(len: usize, index: i64) -> Option<usize> {
+    let i = match index {
+        1.. => index as usize - 1,
+        0 => return None,
+        _ => len.checked_sub(index.unsigned_abs() as usize)?,
+    };
+    (i < len).then_some(i)
+}
+
+impl FrameStats {
+    pub fn new(m: &Model) -> Self {
+        let mut s = Self {
+            vertices: m.v.len(),
+            triangles: m.f.len(),
+            ..Default::default()
+        };
+        for p in m.v.iter() {
+            if p.iter().all(|x| x.is_finite()) {
+                let (min, max) = s
+                    .bbox
+                    .get_or_insert(([p[0], p[1], p[2]], [p[0], p[1], p[2]]));
+                for c in 0..3 {
+                    min[c] = min[c].min(p[c]);
+                    max[c] = max[c].max(p[c]);
+                }
+            } else {
+                s.non_finite += 1;
+            }
+        }
+        s.unnormalized =
+            m.vn.iter()
+                .filter(|n| {
+                    let len = n.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    len.is_nan() || (len - 1.0).abs() > NORMAL_EPS
+                })
+                .count();
+        s.uv_out_of_range =
+            m.vt.iter()
+                .filter(|uv| !uv.iter().all(|x| (0.0..=1.0).contains(x)))
+                .count();
+
+        let mut hasher = DefaultHasher::new();
+        for f in m.f.iter() {
+            let resolved: Option<Vec<usize>> = f
+                .iter()
+                .map(|point| match point.as_slice() {
+                    &[p, t, n] => {
+                        resolve(m.vt.len(), t)?;
+                        resolve(m.vn.len(), n)?;
+                        resolve(m.v.len(), p)
+                    }
+                    _ => None,
+                })
+                .collect();
+            let Some(idx) = resolved else {
+                s.invalid_faces += 1;
+                continue;
+            };
+            idx.hash(&mut hasher);
+            let [a, b, c] = [&m.v[idx[0]], &m.v[idx[1]], &m.v[idx[2]]];
+            let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+            let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+            let cross = [
+                u[1] * v[2] - u[2] * v[1],
+                u[2] * v[0] - u[0] * v[2],
+                u[0] * v[1] - u[1] * v[0],
+            ];
+            let area2 = cross.iter().map(|x| x * x).sum::<f32>();
+            if idx[0] == idx[1] || idx[1] == idx[2] || idx[2] == idx[0] || area2 < AREA_EPS {
+                s.degenerate += 1;
+            }
+        }
+        s.topology = hasher.finish();
+        s
+    }
+    /// 問題のある要素の合計
+    pub fn problems(&self) -> usize {
+        self.degenerate
+            + self.invalid_faces
+            + self.non_finite
+            + self.unnormalized
+            + self.uv_out_of_range
+    }
+    /// 前のフレームと頂点数や面の構成が違うか
+    pub fn topology_changed(&self, prev: &Self) -> bool {
+        self.vertices != prev.vertices || self.topology != prev.topology
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wavefrontobj::parse_model;
+
+    const TRIANGLE: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvn 0 0 1\n\
+                            f 1/1/1 2/2/1 3/1/1\n";
+
+    fn stats(obj: &str) -> FrameStats {
+        FrameStats::new(&parse_model(obj.as_bytes()).unwrap().1)
+    }
+
+    #[test]
+    fn test_clean() {
+        let s = stats(TRIANGLE);
+        assert_eq!((s.vertices, s.triangles), (3, 1));
+        assert_eq!(s.bbox, Some(([0.0; 3], [1.0, 1.0, 0.0])));
+        assert_eq!(s.problems(), 0);
+    }
+
+    #[test]
+    fn test_problems() {
+        let s = stats(&format!(
+            "{}v nan 0 0\nvn 0 0 2\nvt 1.5 0\nf 1/1/1 1/1/1 2/1/1\nf 1/1/1 2/1/1 9/1/1\n",
+            TRIANGLE
+        ));
+        assert_eq!(s.non_finite, 1);
+        assert_eq!(s.unnormalized, 1);
+        assert_eq!(s.uv_out_of_range, 1);
+        assert_eq!(s.degenerate, 1);
+        assert_eq!(s.invalid_faces, 1);
+        assert_eq!(s.bbox, Some(([0.0; 3], [1.0, 1.0, 0.0])));
+    }
+
+    #[test]
+    fn test_topology() {
+        let a = stats(TRIANGLE);
+        let moved = stats(&TRIANGLE.replace("v 1 0 0", "v 2 0 0"));
+        let flipped = stats(&TRIANGLE.replace("f 1/1/1 2/2/1 3/1/1", "f 1/1/1 3/1/1 2/2/1"));
+        assert!(!moved.topology_changed(&a));
+        assert!(flipped.topology_changed(&a));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::wavefrontobj::FLOAT_NUM;
+
+/// 変換時に全フレームへ適用する座標変換
+/// 軸の入れ替え，鏡映，一様スケール，平行移動の組み合わせのみを扱う
+/// (法線には線形部分をそのまま掛けて正規化する)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    /// 線形部分 (行優先)
+    pub linear: [[f32; 3]; 3],
+    pub translation: [f32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self::linear([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+    fn linear(linear: [[f32; 3]; 3]) -> Self {
+        Self {
+            linear,
+            translation: [0.0; 3],
+        }
+    }
+    /// Y-upのデータをZ-upにする (x軸まわりに90度回転)
+    pub fn y_up_to_z_up() -> Self {
+        Self::linear([[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]])
+    }
+    /// Z-upのデータをY-upにする
+    pub fn z_up_to_y_up() -> Self {
+        Self::linear([[1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]])
+    }
+    pub fn scale(s: f32) -> Self {
+        Self::linear([[s, 0.0, 0.0], [0.0, s, 0.0], [0.0, 0.0, s]])
+    }
+    pub fn translate(t: [f32; 3]) -> Self {
+        Self {
+            translation: t,
+            ..Self::identity()
+        }
+    }
+    /// axis軸(0: x, 1: y, 2: z)を反転して右手系と左手系を入れ替える
+    pub fn mirror(axis: usize) -> Self {
+        let mut t = Self::identity();
+        t.linear[axis][axis] = -1.0;
+        t
+    }
+    /// selfの後にnextを適用する変換
+    pub fn then(self, next: Self) -> Self {
+        let mut linear = [[0.0; 3]; 3];
+        for (i, row) in linear.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0..3).map(|k| next.linear[i][k] * self.linear[k][j]).sum();
+            }
+        }
+        let mut translation = next.apply_linear(self.translation);
+        for (t, n) in translation.iter_mut().zip(next.translation) {
+            *t += n;
+        }
+        Self {
+            linear,
+            translation,
+        }
+    }
+    fn apply_linear(&self, p: [f32; 3]) -> [f32; 3] {
+        self.linear
+            .map(|row| row[0] * p[0] + row[1] * p[1] + row[2] * p[2])
+    }
+    pub fn apply_point(&self, p: [f32; 3]) -> [f32; 3] {
+        let mut q = self.apply_linear(p);
+        for (q, t) in q.iter_mut().zip(self.translation) {
+            *q += t;
+        }
+        q
+    }
+    pub fn apply_normal(&self, n: [f32; 3]) -> [f32; 3] {
+        let n = self.apply_linear(n);
+        let len = n.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if len > 0.0 {
+            n.map(|x| x / len)
+        } else {
+            n
+        }
+    }
+    /// 行列式が負なら三角形の表裏が入れ替わる
+    pub fn flips_winding(&self) -> bool {
+        let m = &self.linear;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        det < 0.0
+    }
+    /// 展開済み頂点配列(point, normal, uv)に適用する
+    /// 表裏が入れ替わる場合は各三角形の2番目と3番目の頂点を入れ替えて向きを戻す
+    pub fn apply_vertex(&self, vertex: &mut [f32]) {
+        for x in vertex.chunks_exact_mut(FLOAT_NUM) {
+            let p = self.apply_point([x[0], x[1], x[2]]);
+            let n = self.apply_normal([x[3], x[4], x[5]]);
+            x[0..3].copy_from_slice(&p);
+            x[3..6].copy_from_slice(&n);
+        }
+        if self.flips_winding() {
+            for tri in vertex.chunks_exact_mut(FLOAT_NUM * 3) {
+                let (a, b) = tri[FLOAT_NUM..].split_at_mut(FLOAT_NUM);
+                a.swap_with_slice(b);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 三角形の頂点の並びから求めた面の向き
+    fn face_normal(tri: &[f32]) -> [f32; 3] {
+        let p = |i: usize| &tri[i * FLOAT_NUM..i * FLOAT_NUM + 3];
+        let u: Vec<f32> = (0..3).map(|c| p(1)[c] - p(0)[c]).collect();
+        let v: Vec<f32> = (0..3).map(|c| p(2)[c] - p(0)[c]).collect();
+        [
+            u[1] * v[2] - u[2] * v[1],
+            u[2] * v[0] - u[0] * v[2],
+            u[0] * v[1] - u[1] * v[0],
+        ]
+    }
+
+    #[rustfmt::skip]
+    const TRIANGLE: [f32; 24] = [
+        0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
+        1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0,
+        0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0,
+    ];
+
+    #[test]
+    fn test_axis() {
+        assert_eq!(
+            Transform::y_up_to_z_up().apply_point([0.0, 1.0, 0.0]),
+            [0.0, 0.0, 1.0]
+        );
+        let t = Transform::y_up_to_z_up().then(Transform::z_up_to_y_up());
+        assert_eq!(t, Transform::identity());
+        assert!(!Transform::y_up_to_z_up().flips_winding());
+    }
+
+    #[test]
+    fn test_compose() {
+        let t = Transform::translate([-1.0, 0.0, 0.0]).then(Transform::scale(2.0));
+        assert_eq!(t.apply_point([1.0, 1.0, 1.0]), [0.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_mirror_winding() {
+        for t in [
+            Transform::mirror(0),
+            Transform::mirror(2).then(Transform::y_up_to_z_up()),
+            Transform::scale(3.0),
+        ] {
+            let mut v = TRIANGLE.to_vec();
+            t.apply_vertex(&mut v);
+            assert_eq!(t.flips_winding(), t != Transform::scale(3.0));
+            // 頂点の並びから求めた向きと法線が揃っている
+            let n = face_normal(&v);
+            let d: f32 = (0..3).map(|c| n[c] * v[3 + c]).sum();
+            assert!(d > 0.0, "{:?}", t);
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
 use std::io::BufRead;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Read, Write};
 use std::str::FromStr;
 
+/// 展開済み頂点配列の1頂点あたりのfloat数
+pub const FLOAT_NUM: usize = 8;
+
+/// objファイルの内容 (インデックスは1始まり，負値は末尾から)
 #[derive(Debug, Default, Clone)]
-struct Model {
+pub struct Model {
     pub v: Vec<Vec<f32>>,
     pub vn: Vec<Vec<f32>>,
     pub vt: Vec<Vec<f32>>,
@@ -34,6 +39,31 @@ impl Model {
         }
         Some(vertex)
     }
+    /// 展開済み頂点配列から同じ値をまとめてインデックス化する
+    pub fn from_vertex(vertex: &[f32]) -> Self {
+        fn index(map: &mut HashMap<Vec<u32>, i64>, v: &mut Vec<Vec<f32>>, x: &[f32]) -> i64 {
+            let key = x.iter().map(|f| f.to_bits()).collect();
+            *map.entry(key).or_insert_with(|| {
+                v.push(x.to_vec());
+                v.len() as i64
+            })
+        }
+        let mut m = Model::default();
+        let (mut vs, mut vns, mut vts) = (HashMap::new(), HashMap::new(), HashMap::new());
+        for tri in vertex.chunks_exact(FLOAT_NUM * 3) {
+            let face = tri
+                .chunks_exact(FLOAT_NUM)
+                .map(|x| {
+                    let p = index(&mut vs, &mut m.v, &x[0..3]);
+                    let n = index(&mut vns, &mut m.vn, &x[3..6]);
+                    let t = index(&mut vts, &mut m.vt, &x[6..8]);
+                    vec![p, t, n]
+                })
+                .collect();
+            m.f.push(face);
+        }
+        m
+    }
 }
 /// Pythonぽい負値インデックスによるアクセス
 fn i_get<T>(v: &Vec<T>, index: i64) -> Option<&T> {
@@ -54,9 +84,15 @@ where
 }
 
 pub fn parse_obj(buf: impl Read) -> Result<(String, Vec<f32>)> {
+    let (mtl, m) = parse_model(buf)?;
+    Ok((mtl, m.to_vertex().ok_or(anyhow!("invalid point index"))?))
+}
+
+/// 頂点配列に展開せずにパースする
+pub fn parse_model(buf: impl Read) -> Result<(String, Model)> {
     let mut f = BufReader::new(buf);
     // let mut ms = HashMap::new();
-    let m = &mut Model::default();
+    let mut m = Model::default();
     let mut mtl = String::new();
     let mut buf = String::new();
 
@@ -93,5 +129,89 @@ pub fn parse_obj(buf: impl Read) -> Result<(String, Vec<f32>)> {
             _ => bail!("invalide input: \n\"{}\"\nparsed: {:?}", buf, split),
         }
     }
-    Ok((mtl, m.to_vertex().ok_or(anyhow!("invalid point index"))?))
+    Ok((mtl, m))
+}
+
+fn write_floats(w: &mut impl Write, tag: &str, v: &[Vec<f32>]) -> io::Result<()> {
+    for x in v {
+        write!(w, "{}", tag)?;
+        for f in x {
+            write!(w, " {}", f)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+/// objとして書き出す
+/// mtlに(mtlファイル, マテリアル名)を指定するとそのマテリアルを参照する
+pub fn write_obj(mut w: impl Write, m: &Model, mtl: Option<(&str, &str)>) -> io::Result<()> {
+    if let Some((lib, _)) = mtl {
+        writeln!(w, "mtllib {}", lib)?;
+    }
+    write_floats(&mut w, "v", &m.v)?;
+    write_floats(&mut w, "vt", &m.vt)?;
+    write_floats(&mut w, "vn", &m.vn)?;
+    if let Some((_, material)) = mtl {
+        writeln!(w, "usemtl {}", material)?;
+    }
+    for f in m.f.iter() {
+        write!(w, "f")?;
+        for point in f {
+            let point: Vec<_> = point.iter().map(|i| i.to_string()).collect();
+            write!(w, " {}", point.join("/"))?;
+        }
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+/// テクスチャを1枚貼るだけのmtlを書き出す
+pub fn write_mtl(mut w: impl Write, material: &str, texture: &str) -> io::Result<()> {
+    writeln!(w, "newmtl {}", material)?;
+    writeln!(w, "Ka 1 1 1")?;
+    writeln!(w, "Kd 1 1 1")?;
+    writeln!(w, "map_Kd {}", texture)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "mtllib quad.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+                        vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n\
+                        f 1/1/1 2/2/1 3/3/1\nf 1/1/1 3/3/1 -1/4/-1\n";
+
+    #[test]
+    fn test_write_model() {
+        let (mtl, m) = parse_model(QUAD.as_bytes()).unwrap();
+        let mut out = vec![];
+        write_obj(&mut out, &m, Some((&mtl, "quad"))).unwrap();
+        assert!(String::from_utf8_lossy(&out).contains("usemtl quad\n"));
+        let (mtl2, m2) = parse_model(out.as_slice()).unwrap();
+        assert_eq!(mtl2, "quad.mtl");
+        assert_eq!(m2.f, m.f);
+        assert_eq!(m2.to_vertex(), m.to_vertex());
+    }
+
+    #[test]
+    fn test_write_vertex() {
+        let (_, vertex) = parse_obj(QUAD.as_bytes()).unwrap();
+        let m = Model::from_vertex(&vertex);
+        assert_eq!((m.v.len(), m.vt.len(), m.vn.len()), (4, 4, 1));
+        let mut out = vec![];
+        write_obj(&mut out, &m, None).unwrap();
+        let (_, vertex2) = parse_obj(out.as_slice()).unwrap();
+        assert_eq!(vertex2, vertex);
+    }
+
+    #[test]
+    fn test_write_mtl() {
+        let mut out = vec![];
+        write_mtl(&mut out, "frame", "frame_0.png").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("newmtl frame\n"));
+        assert!(out.contains("map_Kd frame_0.png\n"));
+    }
 }
@@ -1,12 +1,228 @@
-use asyncfileio::FileConverter;
-use parser::wavefrontobj::parse_obj;
+use anyhow::{ensure, Result};
+use asyncfileio::{Buffer, FileConverter, PackStats};
+use bcn::{Dds, Format};
+use parser::stats::FrameStats;
+use parser::transform::Transform;
+use parser::wavefrontobj::{parse_model, parse_obj};
 use std::path::PathBuf;
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let src = std::env::args().nth(1).unwrap();
-    FileConverter::spawn(vec![src], |s, buf| {
-        let (_, b) = parse_obj(buf.as_ref().as_slice())?;
-        Ok((PathBuf::from(s).with_extension("vertex"), b))
-    })
-    .stop()?;
+
+fn obj_to_vertex(buf: Buffer, transform: &Transform) -> Result<Vec<f32>> {
+    let (_, mut b) = parse_obj(buf.as_ref())?;
+    transform.apply_vertex(&mut b);
+    Ok(b)
+}
+
+fn image_to_dds(format: Format, buf: Buffer) -> Result<Vec<u8>> {
+    // Textureと同じく上下反転しておき，そのままアップロードできるようにする
+    let im = image::load_from_memory(buf.as_ref())?.flipv().to_rgba();
+    let (w, h) = im.dimensions();
+    Ok(Dds::encode(format, w as _, h as _, &im.into_raw()).to_bytes())
+}
+
+/// オプションの値を取り出す
+fn value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow::anyhow!("require argment {}", name))
+}
+
+/// "x,y,z"形式のベクトル
+fn vec3(s: &str) -> Result<[f32; 3]> {
+    let v = s
+        .split(',')
+        .map(|x| x.trim().parse())
+        .collect::<Result<Vec<f32>, _>>()?;
+    v.try_into()
+        .map_err(|v| anyhow::anyhow!("expected x,y,z but got {:?}", v))
+}
+
+fn axis(s: &str) -> Result<usize> {
+    match s {
+        "x" => Ok(0),
+        "y" => Ok(1),
+        "z" => Ok(2),
+        _ => anyhow::bail!("unknown axis: {}", s),
+    }
+}
+
+fn report(dst: &str, stats: PackStats) {
+    eprintln!(
+        "{}: {} frames, {} unique payloads, {} bytes deduplicated",
+        dst, stats.frames, stats.payloads, stats.saved_bytes
+    );
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn stats_json(path: &str, s: &FrameStats, topology_changed: bool) -> String {
+    let bbox = match s.bbox {
+        Some((min, max)) => format!(
+            "{{\"min\":[{},{},{}],\"max\":[{},{},{}]}}",
+            min[0], min[1], min[2], max[0], max[1], max[2]
+        ),
+        None => "null".to_owned(),
+    };
+    format!(
+        "{{\"path\":{},\"vertices\":{},\"triangles\":{},\"bbox\":{},\"degenerate\":{},\
+         \"invalid_faces\":{},\"non_finite\":{},\"unnormalized\":{},\"uv_out_of_range\":{},\
+         \"topology_changed\":{}}}",
+        json_string(path),
+        s.vertices,
+        s.triangles,
+        bbox,
+        s.degenerate,
+        s.invalid_faces,
+        s.non_finite,
+        s.unnormalized,
+        s.uv_out_of_range,
+        topology_changed
+    )
+}
+
+fn stats_text(path: &str, s: &FrameStats, topology_changed: bool) {
+    print!(
+        "{}: {} vertices, {} triangles",
+        path, s.vertices, s.triangles
+    );
+    match s.bbox {
+        Some((min, max)) => println!(", bbox {:?}-{:?}", min, max),
+        None => println!(", empty"),
+    }
+    for (name, n) in [
+        ("degenerate triangles", s.degenerate),
+        ("invalid faces", s.invalid_faces),
+        ("NaN/Inf positions", s.non_finite),
+        ("unnormalized normals", s.unnormalized),
+        ("out-of-range UVs", s.uv_out_of_range),
+    ] {
+        if n > 0 {
+            println!("  {}: {}", name, n);
+        }
+    }
+    if topology_changed {
+        println!("  topology changed from previous frame");
+    }
+}
+
+/// 変換せずに各フレームを検査する
+/// 問題のあるフレームがあればエラーを返す
+fn check_frames(objs: &[String], json: bool) -> Result<()> {
+    let mut prev: Option<FrameStats> = None;
+    let mut broken = 0;
+    let mut entries = vec![];
+    for path in objs {
+        let (_, model) = parse_model(std::fs::File::open(path)?)?;
+        let s = FrameStats::new(&model);
+        let changed = prev.as_ref().is_some_and(|p| s.topology_changed(p));
+        if s.problems() > 0 {
+            broken += 1;
+        }
+        if json {
+            entries.push(stats_json(path, &s, changed));
+        } else {
+            stats_text(path, &s, changed);
+        }
+        prev = Some(s);
+    }
+    if json {
+        println!("[{}]", entries.join(",\n"));
+    }
+    ensure!(
+        broken == 0,
+        "{} of {} frames have problems",
+        broken,
+        objs.len()
+    );
+    Ok(())
+}
+
+/// objは頂点配列(.vertex)に，画像はブロック圧縮テクスチャ(.dds)に変換する
+/// --*-packを指定すると連番を重複排除して1つのパックファイルにまとめる
+/// --statsを指定すると変換せずにobjを検査する (--jsonでJSON出力)
+/// objには --origin, --axis, --flip, --scale の順に座標変換を適用する
+/// usage: aot_parse [--format bc1|bc3|bc7] [--vertex-pack dst] [--texture-pack dst]
+///                  [--stats [--json]] [--origin x,y,z] [--axis y-to-z|z-to-y]
+///                  [--flip x|y|z] [--scale s] <src>...
+fn main() -> Result<()> {
+    let mut format = Format::Bc1;
+    let mut origin = None;
+    let mut up = None;
+    let mut flip = None;
+    let mut scale = None;
+    let mut stats = false;
+    let mut json = false;
+    let mut vertex_pack = None;
+    let mut texture_pack = None;
+    let mut objs = vec![];
+    let mut images = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = value(&mut args, "format")?.parse()?,
+            "--vertex-pack" => vertex_pack = Some(value(&mut args, "vertex-pack")?),
+            "--texture-pack" => texture_pack = Some(value(&mut args, "texture-pack")?),
+            "--origin" => origin = Some(vec3(&value(&mut args, "origin")?)?),
+            "--axis" => {
+                up = Some(match value(&mut args, "axis")?.as_str() {
+                    "y-to-z" => Transform::y_up_to_z_up(),
+                    "z-to-y" => Transform::z_up_to_y_up(),
+                    a => anyhow::bail!("unknown axis conversion: {}", a),
+                })
+            }
+            "--flip" => flip = Some(axis(&value(&mut args, "flip")?)?),
+            "--scale" => scale = Some(value(&mut args, "scale")?.parse()?),
+            "--stats" => stats = true,
+            "--json" => json = true,
+            _ if arg.ends_with(".obj") => objs.push(arg),
+            _ => images.push(arg),
+        }
+    }
+    if stats {
+        return check_frames(&objs, json);
+    }
+    let mut transform = Transform::identity();
+    if let Some(o) = origin {
+        transform = transform.then(Transform::translate(o.map(|x: f32| -x)));
+    }
+    if let Some(t) = up {
+        transform = transform.then(t);
+    }
+    if let Some(a) = flip {
+        transform = transform.then(Transform::mirror(a));
+    }
+    if let Some(s) = scale {
+        transform = transform.then(Transform::scale(s));
+    }
+    match vertex_pack {
+        Some(dst) => {
+            let stats = FileConverter::pack(objs, PathBuf::from(&dst), move |_, buf| {
+                obj_to_vertex(buf, &transform)
+            })?;
+            report(&dst, stats);
+        }
+        None => FileConverter::spawn(objs, move |s, buf| {
+            Ok((
+                PathBuf::from(s).with_extension("vertex"),
+                obj_to_vertex(buf, &transform)?,
+            ))
+        })
+        .stop()?,
+    }
+    match texture_pack {
+        Some(dst) => {
+            let stats = FileConverter::pack(images, PathBuf::from(&dst), move |_, buf| {
+                image_to_dds(format, buf)
+            })?;
+            report(&dst, stats);
+        }
+        None => FileConverter::spawn(images, move |s, buf| {
+            Ok((
+                PathBuf::from(s).with_extension("dds"),
+                image_to_dds(format, buf)?,
+            ))
+        })
+        .stop()?,
+    }
     Ok(())
 }
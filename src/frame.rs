@@ -0,0 +1,52 @@
+use anyhow::{ensure, Result};
+use asyncfileio::{Buffer, Joined, Part};
+use util::SliceAs;
+
+use crate::image_manager::TextureData;
+
+/// 頂点とテクスチャが揃った1フレーム
+pub struct Frame {
+    /// 読み込んだまま (mmapした領域ならコピーしない)
+    vertex: Part,
+    texture: TextureData,
+    texture_payload: usize,
+}
+
+impl Frame {
+    /// Joined::new(vec![頂点, テクスチャ])で読んだ内容を分ける
+    /// ブロッキングスレッドで実行する
+    pub fn decode(buf: Buffer) -> Result<Self> {
+        let mut parts = Joined::parts(buf)?;
+        ensure!(parts.len() == 2, "frame requires vertex and texture");
+        let texture = parts.pop().unwrap();
+        let vertex = parts.pop().unwrap();
+        unsafe { vertex.buf.slice_as::<f32>()? };
+        Ok(Self {
+            texture: TextureData::decode(&texture.buf)?,
+            texture_payload: texture.payload,
+            vertex,
+        })
+    }
+    pub fn vertex(&self) -> &[f32] {
+        unsafe { self.vertex.buf.slice_as_unchecked() }
+    }
+    pub fn texture(&self) -> &TextureData {
+        &self.texture
+    }
+    /// 同じなら頂点の内容も同じ
+    pub fn vertex_payload(&self) -> usize {
+        self.vertex.payload
+    }
+    /// 同じならテクスチャの内容も同じ
+    pub fn texture_payload(&self) -> usize {
+        self.texture_payload
+    }
+    /// 読み込んだ頂点のメモリ量
+    pub fn vertex_bytes(&self) -> usize {
+        self.vertex.buf.capacity()
+    }
+    /// 展開したテクスチャのメモリ量
+    pub fn texture_bytes(&self) -> usize {
+        self.texture.byte_size()
+    }
+}
@@ -1,5 +1,47 @@
+use anyhow::Result;
+use bcn::{Dds, Format};
+use gl::types::GLenum;
 use image::GenericImageView;
 
+// S3TCは拡張なのでglクレートに定義がない
+const COMPRESSED_RGB_S3TC_DXT1_EXT: GLenum = 0x83F0;
+const COMPRESSED_RGBA_S3TC_DXT5_EXT: GLenum = 0x83F3;
+
+/// デコード済みのテクスチャ
+pub enum TextureData {
+    Image(image::DynamicImage),
+    /// aot_parseで変換済みのブロック圧縮テクスチャ
+    Compressed(Dds),
+}
+
+impl TextureData {
+    /// 先頭のマジックナンバーでDDSか通常の画像か判別する
+    /// ブロッキングスレッドで実行する
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(if bcn::dds::is_dds(buf) {
+            Self::Compressed(Dds::from_bytes(buf)?)
+        } else {
+            Self::Image(image::load_from_memory(buf)?)
+        })
+    }
+    /// 展開後のメモリ量
+    pub fn byte_size(&self) -> usize {
+        match self {
+            Self::Image(im) => {
+                let (w, h) = im.dimensions();
+                w as usize * h as usize * im.color().bits_per_pixel() as usize / 8
+            }
+            Self::Compressed(dds) => dds.data.len(),
+        }
+    }
+    pub fn upload(&self) -> Texture {
+        match self {
+            Self::Image(im) => Texture::new(im, true),
+            Self::Compressed(dds) => Texture::compressed(dds),
+        }
+    }
+}
+
 pub struct Texture(u32);
 impl Drop for Texture {
     fn drop(&mut self) {
@@ -24,15 +66,9 @@ impl Texture {
         //     im = &im.flipv();
         // }
         let data = im.raw_pixels();
-        let mut texture = 0;
 
         unsafe {
-            gl::GenTextures(1, &mut texture);
-            gl::BindTexture(gl::TEXTURE_2D, texture);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            let texture = Self::generate();
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
@@ -46,7 +82,50 @@ impl Texture {
             );
             gl::GenerateMipmap(gl::TEXTURE_2D);
             gl::BindTexture(gl::TEXTURE_2D, 0);
+            texture
+        }
+    }
+    /// 読み込めなかったフレームの代わりに貼る市松模様
+    pub fn placeholder() -> Self {
+        let im = image::RgbaImage::from_fn(8, 8, |x, y| match (x + y) % 2 {
+            0 => image::Rgba([255, 0, 255, 255]),
+            _ => image::Rgba([0, 0, 0, 255]),
+        });
+        Self::new(&image::DynamicImage::ImageRgba8(im), true)
+    }
+    /// ブロック圧縮されたデータをそのまま転送する
+    /// 変換時に上下反転済みなのでここでは反転しない
+    pub fn compressed(dds: &Dds) -> Self {
+        let format = match dds.format {
+            Format::Bc1 => COMPRESSED_RGB_S3TC_DXT1_EXT,
+            Format::Bc3 => COMPRESSED_RGBA_S3TC_DXT5_EXT,
+            Format::Bc7 => gl::COMPRESSED_RGBA_BPTC_UNORM,
+        };
+        unsafe {
+            let texture = Self::generate();
+            gl::CompressedTexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                format,
+                dds.width as i32,
+                dds.height as i32,
+                0,
+                dds.data.len() as i32,
+                dds.data.as_ptr() as _,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            texture
         }
+    }
+    /// テクスチャを生成してバインドした状態で返す
+    unsafe fn generate() -> Self {
+        let mut texture = 0;
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
         Self(texture)
     }
     pub fn using(&self, f: impl FnOnce() -> ()) {
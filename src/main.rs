@@ -2,6 +2,7 @@ use std::cell::Cell;
 use std::collections::VecDeque;
 use std::mem;
 use std::os::raw::c_void;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -13,13 +14,19 @@ use imgui::im_str;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
-use cacher::Cacher;
-use util::SliceAs;
+use asyncfileio::{
+    Archive, Change, HttpSource, Joined, Pack, PathList, PlayMode, Playhead, ReaderConfig, Source,
+    WatchFolder,
+};
+use cacher::{Cache, Cacher, Msg};
+use parser::wavefrontobj::FLOAT_NUM;
 
+mod frame;
 mod image_manager;
 mod shader;
 mod vertex;
 
+use frame::Frame;
 use image_manager::Texture;
 use shader::Shader;
 use vertex::Vertex;
@@ -33,7 +40,6 @@ type Matrix4 = cgmath::Matrix4<f32>;
 
 const WINDOW_WIDTH: u32 = 1920;
 const WINDOW_HEIGHT: u32 = 1080;
-const FLOAT_NUM: usize = 8;
 
 fn new_vertex(buf: &[f32]) -> Vertex {
     Vertex::new(
@@ -47,6 +53,15 @@ fn new_vertex(buf: &[f32]) -> Vertex {
     )
 }
 
+/// 同じフレームを指しているか (どちらも読み込みに失敗した場合を含む)
+fn same<T>(a: &Option<Rc<T>>, b: &Option<Rc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
 /// イベントループの1ループごとにsetを呼び出して，fpsを測定
 /// 1秒前までにsetされた回数をカウント
 #[derive(Debug, Default)]
@@ -127,34 +142,89 @@ impl Sleeper {
     }
 }
 
+/// パックファイル，または"{}"を連番に置き換えたファイル列
+/// mmap: ファイルをコピーせずにマップして読む
+fn open_source(
+    pattern: &str,
+    start: Option<usize>,
+    last: Option<usize>,
+    flags: &[String],
+) -> Source {
+    let mmap = flags.iter().any(|f| f == "--mmap");
+    if pattern.ends_with(".pack") {
+        let pack = match mmap {
+            true => Pack::open_mapped(pattern),
+            false => Pack::open(pattern),
+        };
+        return Source::new(pack.expect("failed to open pack"));
+    }
+    // shot.zip#.obj のように#の後ろでアーカイブ内のファイルを絞り込む
+    let (archive, suffix) = pattern.split_once('#').unwrap_or((pattern, ""));
+    if archive.ends_with(".zip") || archive.ends_with(".tar") {
+        let mut archive = Archive::open(archive, suffix).expect("failed to open archive");
+        if mmap {
+            archive = archive.mapped().expect("failed to map archive");
+        }
+        return Source::new(archive);
+    }
+    if flags.iter().any(|f| f == "--watch") {
+        let start = start.unwrap_or(0);
+        return Source::new(WatchFolder::open(pattern, start).expect("failed to watch folder"));
+    }
+    let path: Vec<_> = pattern.split("{}").collect();
+    let start = start.expect("require argment start");
+    let last = last.expect("require argment last");
+    if pattern.starts_with("http://") {
+        return Source::new(HttpSource::new(
+            (start..=last)
+                .map(|i| format!("{}{}{}", path[0], i, path[1]))
+                .collect(),
+        ));
+    }
+    let paths = PathList::new(Arc::new(
+        (start..=last)
+            .map(|i| format!("{}{}{}", path[0], i, path[1]))
+            .collect(),
+    ));
+    match mmap {
+        true => Source::new(paths.mapped()),
+        false => Source::new(paths),
+    }
+}
+
 ///コマンドライン引数を解析してイベントループに渡す
+///パックファイルやアーカイブ (shot.zip#.obj) を渡す場合はstartとlastを省略できる
+///http://で始まるパターンはHTTPサーバから読む
+///--mmapを付けるとファイルをmmapして読む
+///--watchを付けるとstartから書き込まれていくファイルを追いかける (lastは不要)
+///--vertex-cache-mb=N，--texture-cache-mb=Nで頂点とテクスチャそれぞれのキャッシュが持つメモリ量を制限する
 fn main() {
-    let mut args = std::env::args();
-    args.next();
+    let (flags, args): (Vec<_>, Vec<_>) =
+        std::env::args().skip(1).partition(|a| a.starts_with("--"));
+    let mut args = args.into_iter();
     let v = args.next().expect("require argment vertex_path");
     let t = args.next().expect("require argment texture_path");
     dbg!((&v, &t));
-    let vertex_path: Vec<_> = v.split("{}").collect();
-    let texture_path: Vec<_> = t.split("{}").collect();
-    let start: usize = args
+    let start: Option<usize> = args
         .next()
-        .expect("require argment start")
-        .parse()
-        .expect("failed to parse start");
-    let last: usize = args
+        .map(|s| s.parse().expect("failed to parse start"));
+    let last: Option<usize> = args
         .next()
-        .expect("require argment last")
-        .parse()
-        .expect("failed to parse last");
+        .map(|s| s.parse().expect("failed to parse last"));
     truth_main(
-        (start..=last)
-            .map(|i| format!("{}{}{}", vertex_path[0], i, vertex_path[1]))
-            .collect(),
-        (start..=last)
-            .map(|i| format!("{}{}{}", texture_path[0], i, texture_path[1]))
-            .collect(),
+        open_source(&v, start, last, &flags),
+        open_source(&t, start, last, &flags),
+        cache_mb(&flags, "--vertex-cache-mb"),
+        cache_mb(&flags, "--texture-cache-mb"),
     );
 }
+/// --name=Nの形のフラグからキャッシュの上限 (MB) を読む
+fn cache_mb(flags: &[String], name: &str) -> Option<usize> {
+    flags.iter().find_map(|f| {
+        let mb = f.strip_prefix(name)?.strip_prefix('=')?;
+        Some(mb.parse().expect("failed to parse cache size"))
+    })
+}
 ///ファイルをすべて読み込んだ時のメモリ量測定用
 #[allow(dead_code)]
 fn bad_main(vertexes: Vec<String>, textures: Vec<String>) {
@@ -184,8 +254,15 @@ fn bad_main(vertexes: Vec<String>, textures: Vec<String>) {
     std::thread::sleep(Duration::from_secs(10));
 }
 ///イベントループの実装
-fn truth_main(vertexes: Vec<String>, textures: Vec<String>) {
-    dbg!(&vertexes[..4]);
+/// 頂点とテクスチャは揃ったフレームだけを1つの再生位置で読む
+/// vertex_mb, texture_mb: 指定するとフレーム数ではなくメモリ量でキャッシュを制限する
+/// 片方だけ指定したらもう片方はメモリ量では制限しない
+fn truth_main(
+    vertexes: Source,
+    textures: Source,
+    vertex_mb: Option<usize>,
+    texture_mb: Option<usize>,
+) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -220,31 +297,28 @@ fn truth_main(vertexes: Vec<String>, textures: Vec<String>) {
     let renderer = imgui_opengl_renderer::Renderer::new(&mut imgui_context, |s| {
         video_subsystem.gl_get_proc_address(s) as _
     });
-    let vertexes = Arc::new(vertexes);
-    let textures = Arc::new(textures);
-    let mut vertex_cache = Cacher::new(5, Arc::clone(&vertexes), |x| x, |b, _| b);
-    let mut texture_cache = Cacher::new(
-        5,
-        Arc::clone(&textures),
-        |x| {
-            // dbg!("decoding");
-            let ret = image::load_from_memory(x.as_ref()).expect("");
-            // dbg!("decoded");
-            ret
-        },
-        |b, _| b,
-    );
-    let mut vertex = loop {
-        if let Some(x) = vertex_cache.get(0) {
-            break new_vertex(unsafe { x.as_ref().as_ref().slice_as().unwrap() });
-        }
-        std::thread::sleep(Duration::from_millis(1));
+    let source = Source::new(Joined::new(vec![vertexes, textures]));
+    let que_max = match vertex_mb.or(texture_mb) {
+        Some(_) => usize::MAX,
+        None => 5,
     };
-    let mut texture = loop {
-        if let Some(x) = texture_cache.get(0) {
-            break Texture::new(&x, true);
-        }
-        std::thread::sleep(Duration::from_millis(1));
+    let budget = |mb: Option<usize>| mb.map_or(usize::MAX, |mb| mb << 20);
+    let store = Cache::new(que_max)
+        .max_bytes(budget(vertex_mb), Frame::vertex_bytes)
+        .max_bytes(budget(texture_mb), Frame::texture_bytes);
+    let mut cache = Cacher::with_cache(
+        store.shared(),
+        |i| i,
+        source.clone(),
+        Frame::decode,
+        |f, _| f,
+    );
+    // 表示中のフレームと転送済みのGPUリソース
+    // 読み込みに失敗したフレームはNoneで，代わりのものを表示する
+    let mut shown = cache.get_blocking(&0, Duration::from_secs(30));
+    let (mut vertex, mut texture) = match &shown {
+        Some(f) => (new_vertex(f.vertex()), f.texture().upload()),
+        None => (new_vertex(&[]), Texture::placeholder()),
     };
 
     let mut depth_test: bool = true;
@@ -254,10 +328,15 @@ fn truth_main(vertexes: Vec<String>, textures: Vec<String>) {
     let mut eye = Point3::new(2.0, 2.0, 2.0);
     let mut center = Point3::new(0.0, 0.0, 0.0);
     let mut up = Vector3::new(0.0, 0.0, 1.0);
-    let mut file_index = 1;
-    let step = 1;
-    let len = vertexes.len();
+    let mut len = source.len();
+    // 0番目は表示済み
+    let mut playhead = Playhead::new(len);
+    playhead.advance();
+    let mut range = (0, len as i32 - 1);
+    let mut speed: f32 = 1.0;
+    let mut lookahead = ReaderConfig::default().lookahead as i32;
     let mut max_fps = 30;
+    let mut paused = false;
     let sleeper = Sleeper::new();
     let mut success_counter = SuccessCounter::new(60);
     let mut fps_bencher = FPSBencher::new();
@@ -330,19 +409,46 @@ fn truth_main(vertexes: Vec<String>, textures: Vec<String>) {
             shader.set_mat4(c_str!("uProjection"), &projection_matrix);
             shader.set_vec3(c_str!("uViewPosition"), eye.x, eye.y, eye.z);
         }
-        let nowi = file_index;
-        match (vertex_cache.get(file_index), texture_cache.get(file_index)) {
-            (Some(v), Some(t)) => {
-                vertex = new_vertex(unsafe {
-                    let x = v.as_ref().as_ref().slice_as().unwrap();
-                    x
-                });
-                texture = Texture::new(&t, true);
-                file_index += step;
-                file_index %= len;
-                success_counter.set(true);
+        // 監視中の連番は書き込まれるにつれて伸びる
+        let changes = cache.take_changes();
+        if cache.len() != len {
+            len = cache.len();
+            playhead.set_len(len);
+        }
+        let nowi = playhead.index();
+        // 表示中のフレームが書き換わったら読み直す
+        if changes.contains(&Change::Modified(nowi)) {
+            cache.query(Msg::Seek {
+                index: nowi,
+                flush: false,
+            });
+        }
+        let f = cache.get(&nowi);
+        // 読み込みに失敗したフレームは待たずに先に進む
+        if f.is_some() || cache.failed(&nowi).is_some() {
+            // 静止したフレームは同じ値を共有しているので転送し直さない
+            if !same(&f, &shown) {
+                let payload = |f: &Option<Rc<Frame>>, p: fn(&Frame) -> usize| f.as_deref().map(p);
+                if payload(&f, Frame::vertex_payload) != payload(&shown, Frame::vertex_payload) {
+                    vertex = match &f {
+                        Some(f) => new_vertex(f.vertex()),
+                        None => new_vertex(&[]),
+                    };
+                }
+                if payload(&f, Frame::texture_payload) != payload(&shown, Frame::texture_payload) {
+                    texture = match &f {
+                        Some(f) => f.texture().upload(),
+                        None => Texture::placeholder(),
+                    };
+                }
+                shown = f;
+            }
+            if !paused {
+                playhead.advance();
             }
-            _ => success_counter.set(false),
+            success_counter.set(true);
+        } else {
+            success_counter.set(false);
         }
         texture.using(|| {
             vertex.draw();
@@ -367,13 +473,104 @@ fn truth_main(vertexes: Vec<String>, textures: Vec<String>) {
                 ));
                 imgui::Slider::new(im_str!("max fps"), 1..=120).build(&ui, &mut max_fps);
                 ui.separator();
-                ui.text(im_str!("show: {}", vertexes[nowi]));
-                ui.text(im_str!("index: {}/{}", nowi, len - 1));
+                ui.text(im_str!("show: {}", source.name(nowi)));
+                if let Some(e) = cache.failed(&nowi) {
+                    ui.text(im_str!("error: {}", e));
+                }
+                ui.text(im_str!("index: {}/{}", nowi, len.saturating_sub(1)));
+                if ui.checkbox(im_str!("Pause"), &mut paused) {
+                    cache.query(if paused { Msg::Pause } else { Msg::Resume });
+                }
+                let mut seek = nowi as i32;
+                if imgui::Slider::new(im_str!("frame"), 0..=len as i32 - 1).build(&ui, &mut seek) {
+                    let index = seek as usize;
+                    cache.query(Msg::Seek { index, flush: true });
+                    playhead.seek(index);
+                }
+                if imgui::Slider::new(im_str!("speed"), -4.0..=4.0).build(&ui, &mut speed) {
+                    playhead.set_speed(speed as f64);
+                    let index = playhead.index();
+                    cache.query(Msg::Speed(speed as f64));
+                    cache.query(Msg::Seek {
+                        index,
+                        flush: false,
+                    });
+                }
+                if imgui::Slider::new(im_str!("lookahead"), 1..=64).build(&ui, &mut lookahead) {
+                    cache.query(Msg::SetLookahead(lookahead as usize));
+                }
+                ui.text(im_str!("mode: {:?}", playhead.mode()));
+                let mut mode = None;
+                if ui.button(im_str!("Once"), [0.0, 0.0]) {
+                    mode = Some(PlayMode::Once);
+                }
+                if ui.button(im_str!("Loop"), [0.0, 0.0]) {
+                    mode = Some(PlayMode::Loop);
+                }
+                if ui.button(im_str!("Ping-Pong"), [0.0, 0.0]) {
+                    mode = Some(PlayMode::PingPong);
+                }
+                imgui::Slider::new(im_str!("range start"), 0..=len as i32 - 1)
+                    .build(&ui, &mut range.0);
+                imgui::Slider::new(im_str!("range end"), 0..=len as i32 - 1)
+                    .build(&ui, &mut range.1);
+                if ui.button(im_str!("Loop Range"), [0.0, 0.0]) {
+                    mode = Some(PlayMode::LoopRange {
+                        start: range.0 as usize,
+                        end: range.1 as usize + 1,
+                    });
+                }
+                if let Some(mode) = mode {
+                    playhead.set_mode(mode);
+                    // 先読みの位置を表示位置に合わせ直す
+                    let index = playhead.index();
+                    cache.query(Msg::SetMode(mode));
+                    cache.query(Msg::Seek {
+                        index,
+                        flush: false,
+                    });
+                }
                 ui.text(im_str!(
                     "hit rate: {}/{}",
                     success_counter.get(),
                     success_counter.len()
                 ));
+                let m = cache.metrics();
+                let store = cache.cache().borrow();
+                ui.separator();
+                ui.text(im_str!(
+                    "read {:.1} MB/s cached vertex {:.1} MB texture {:.1} MB",
+                    m.bytes_per_sec() / 1e6,
+                    store.bytes_of(0) as f64 / 1e6,
+                    store.bytes_of(1) as f64 / 1e6
+                ));
+                ui.text(im_str!(
+                    "  read {:.1?} (p95 {:.1?}) decode {:.1?} (p95 {:.1?})",
+                    m.read.mean(),
+                    m.read.percentile(0.95),
+                    m.decode.mean(),
+                    m.decode.percentile(0.95)
+                ));
+                ui.text(im_str!(
+                    "  queue {}/{} in flight {}",
+                    m.queued,
+                    m.queue_capacity,
+                    m.in_flight
+                ));
+                ui.text(im_str!(
+                    "  delivered {}/{} failed {} dropped {}",
+                    m.delivered,
+                    m.requested,
+                    m.failed,
+                    m.cancelled + m.discarded
+                ));
+                ui.text(im_str!(
+                    "  pool hit {}/{} held {} ({:.1} MB)",
+                    m.pool.hits,
+                    m.pool.hits + m.pool.misses,
+                    m.pool.buffers_held,
+                    m.pool.bytes_held as f64 / 1e6
+                ));
                 ui.separator();
                 let display_size = ui.io().display_size;
                 ui.text(format!(
@@ -1,4 +1,4 @@
-use std::mem::size_of;
+use std::mem::{align_of, size_of};
 use std::{error, fmt};
 
 #[derive(Debug, Clone, Copy)]
@@ -6,6 +6,8 @@ use std::{error, fmt};
 pub struct SliceAsError {
     from: usize,
     to: usize,
+    /// 先頭のアドレスがこれの倍数でない
+    align: usize,
 }
 
 impl fmt::Display for SliceAsError {
@@ -30,8 +32,9 @@ impl<F> SliceAs for [F] {
     unsafe fn slice_as<T>(&self) -> Result<&[T], SliceAsError> {
         let from = size_of::<F>();
         let to = size_of::<T>();
-        if self.len() * from % to != 0 {
-            return Err(SliceAsError { to, from });
+        let align = align_of::<T>();
+        if self.len() * from % to != 0 || self.as_ptr() as usize % align != 0 {
+            return Err(SliceAsError { to, from, align });
         }
         Ok(self.slice_as_unchecked())
     }
@@ -63,5 +66,10 @@ mod tests {
         let y: &[u16] = unsafe { x.slice_as() }.unwrap();
         dbg!(format!("{:x}", y[0]));
         assert_eq!(y.len(), 1);
+
+        // 境界がずれていれば失敗する
+        let x = [0_u32; 2];
+        let y: &[u8] = unsafe { x.slice_as() }.unwrap();
+        assert!(unsafe { y[1..5].slice_as::<u32>() }.is_err());
     }
 }